@@ -1,8 +1,10 @@
 //! https://cdn.sparkfun.com/datasheets/Sensors/Accelerometers/MMA8452Q-rev8.1.pdf
 
 extern crate tessel;
+#[macro_use] extern crate tracing;
 
 use std::io;
+use tessel::sensor::{Acceleration, Sensor, G};
 
 #[repr(u8)]
 pub enum ScaleRange {
@@ -41,6 +43,8 @@ pub struct Accelerometer<'a> {
     i2c: tessel::I2cPort<'a>,
     i1: tessel::Pin<'a>,
     i2: tessel::Pin<'a>,
+    // Full scale of the configured range, in g.
+    scale: f64,
 }
 
 impl<'a> Accelerometer<'a> {
@@ -52,6 +56,7 @@ impl<'a> Accelerometer<'a> {
             i2c: i2c,
             i1: i1,
             i2: i2,
+            scale: 2.0,
         }
     }
 
@@ -73,7 +78,10 @@ impl<'a> Accelerometer<'a> {
     }
 
     pub fn connect(&mut self) -> io::Result<()> {
-        if try!(self.read_register(Command::WhoAmI)) != 0x2A {
+        let who_am_i = try!(self.read_register(Command::WhoAmI));
+        debug!(who_am_i, "read MMA8452 WHO_AM_I");
+        if who_am_i != 0x2A {
+            warn!(who_am_i, "unexpected MMA8452 WHO_AM_I");
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid connection code."))
         }
 
@@ -96,9 +104,15 @@ impl<'a> Accelerometer<'a> {
     }
 
     pub fn set_scale_range(&mut self, range: ScaleRange) -> io::Result<()> {
+        let scale = match range {
+            ScaleRange::Scale2G => 2.0,
+            ScaleRange::Scale4G => 4.0,
+            ScaleRange::Scale8G => 8.0,
+        };
         try!(self.standby_enable());
         try!(self.write_register(Command::XyzDataCfg, range as u8));
         try!(self.standby_disable());
+        self.scale = scale;
 
         Ok(())
     }
@@ -116,6 +130,7 @@ impl<'a> Accelerometer<'a> {
         Ok(())
     }
 
+    /// Reads the acceleration along each axis, in g.
     pub fn read_acceleration(&mut self) -> io::Result<(f64, f64, f64)> {
         let mut buf = [0; 6];
         try!(self.read_registers(Command::OutXMsb, &mut buf));
@@ -136,10 +151,19 @@ impl<'a> Accelerometer<'a> {
                 g as i16
             };
 
-            let scale_range = 2.0;
-            out[i] = (dim as f64) / ((1 << 11) as f64) * scale_range;
+            out[i] = (dim as f64) / ((1 << 11) as f64) * self.scale;
         }
 
+        trace!(x = out[0], y = out[1], z = out[2], "read acceleration");
         Ok((out[0], out[1], out[2]))
     }
 }
+
+impl<'a> Sensor for Accelerometer<'a> {
+    type Measurement = Acceleration;
+
+    fn measure(&mut self) -> io::Result<Acceleration> {
+        let (x, y, z) = try!(self.read_acceleration());
+        Ok(Acceleration { x: G(x), y: G(y), z: G(z) })
+    }
+}
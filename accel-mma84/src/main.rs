@@ -3,7 +3,7 @@ extern crate tessel;
 
 use accel_mma84::Accelerometer;
 use tessel::Tessel;
-use std::thread::sleep;
+use tessel::scheduler::Scheduler;
 use std::time::Duration;
 
 fn main() {
@@ -15,10 +15,15 @@ fn main() {
     acc.connect().expect("Could not connect to accelerometer.");
 
     println!("Reading acceleration sensor... (Press CTRL + C to stop)");
-    loop {
-        println!("Acceleration (x, y, z): {:?}", acc.read_acceleration());
-
-        // Continue the loop after sleeping for 100ms.
-        sleep(Duration::from_millis(100));
+    let mut scheduler = Scheduler::new();
+    let readings = scheduler.add("accel", acc, Duration::from_millis(100));
+    for reading in readings {
+        match reading {
+            Ok(reading) => {
+                let a = reading.value;
+                println!("Acceleration (x, y, z): {}, {}, {}", a.x, a.y, a.z);
+            }
+            Err(e) => println!("Could not read acceleration: {}", e),
+        }
     }
 }
@@ -1,15 +1,18 @@
 //! https://www.silabs.com/Support%20Documents%2FTechnicalDocs%2FSi7020-A20.pdf
 
 extern crate tessel;
+#[macro_use] extern crate tracing;
 
 use std::io;
 use std::thread;
 use std::time::Duration;
+use tessel::sensor::{self, Celsius, RelativeHumidity, Sensor};
 
 #[repr(u8)]
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 enum Command {
+    HumidityHold = 0xE5,
     TempHold = 0xE3,
 
     ReadId1 = 0xFA,
@@ -45,7 +48,7 @@ impl<'a> Climate<'a> {
     }
 
     /// Reads sequential buffers.
-    fn read(&mut self, values: &[Command], buf: &mut [u8]) -> io::Result<()> {
+    fn read_registers(&mut self, values: &[Command], buf: &mut [u8]) -> io::Result<()> {
         let a: Vec<u8> = values.iter().map(|x| *x as u8).collect();
         try!(self.i2c.transfer(I2C_ID, &a, buf));
         Ok(())
@@ -61,26 +64,58 @@ impl<'a> Climate<'a> {
     pub fn connect(&mut self) -> io::Result<()> {
         let mut buf = [0; 6];
         thread::sleep(Duration::from_millis(30)); //WAKE_UP_TIME
-        println!("hi");
-        try!(self.read(&[Command::ReadId3, Command::ReadId4], &mut buf));
-        println!("hey");
-        println!("hi {:?}", buf);
+        try!(self.read_registers(&[Command::ReadId3, Command::ReadId4], &mut buf));
+        debug!(id = ?buf, "read Si7020 electronic ID");
         if buf[0] != 0x14 {
+            warn!(device_id = buf[0], "unexpected Si7020 device ID");
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid connection code."))
         }
         Ok(())
     }
 
+    /// Reads the temperature in Fahrenheit. `Sensor::read` gives the
+    /// temperature in Celsius along with the humidity.
     pub fn read_temperature(&mut self) -> io::Result<f64> {
+        let temp = try!(self.read_celsius());
+
+        // Convert to fahrenheit.
+        Ok((temp * (9.0/5.0)) + 32.0)
+    }
+
+    fn read_celsius(&mut self) -> io::Result<f64> {
         let mut buf = [0; 2];
-        try!(self.read(&[Command::TempHold], &mut buf));
+        try!(self.read_registers(&[Command::TempHold], &mut buf));
 
         let raw_temp = ((buf[0] as u16) << 8) + (buf[1] as u16);
-        let mut temp = ((raw_temp as f64) * TEMPERATURE_SLOPE) - TEMPERATURE_OFFSET;
-
-        // Convert to fahrenheit.
-        temp = (temp * (9.0/5.0)) + 32.0;
+        let temp = ((raw_temp as f64) * TEMPERATURE_SLOPE) - TEMPERATURE_OFFSET;
+        trace!(raw = raw_temp, celsius = temp, "read temperature");
 
         Ok(temp)
     }
+
+    /// Reads the relative humidity, in percent.
+    pub fn read_humidity(&mut self) -> io::Result<f64> {
+        let mut buf = [0; 2];
+        try!(self.read_registers(&[Command::HumidityHold], &mut buf));
+
+        let raw_humidity = ((buf[0] as u16) << 8) + (buf[1] as u16);
+        // Readings slightly outside 0-100% are possible; clamp them as the datasheet advises.
+        let humidity = (((raw_humidity as f64) * HUMIDITY_SLOPE) - HUMIDITY_OFFSET).max(0.0).min(100.0);
+        trace!(raw = raw_humidity, humidity, "read humidity");
+
+        Ok(humidity)
+    }
+}
+
+impl<'a> Sensor for Climate<'a> {
+    type Measurement = sensor::Climate;
+
+    fn measure(&mut self) -> io::Result<sensor::Climate> {
+        let temperature = try!(self.read_celsius());
+        let humidity = try!(self.read_humidity());
+        Ok(sensor::Climate {
+            temperature: Celsius(temperature),
+            humidity: RelativeHumidity(humidity),
+        })
+    }
 }
@@ -3,7 +3,8 @@ extern crate tessel;
 
 use climate_si7020::Climate;
 use tessel::Tessel;
-use std::thread::sleep;
+use tessel::scheduler::Scheduler;
+use tessel::sensor::Fahrenheit;
 use std::time::Duration;
 
 fn main() {
@@ -15,10 +16,15 @@ fn main() {
     climate.connect().expect("Could not connect to climate sensor.");
 
     println!("Reading climate sensor... (Press CTRL + C to stop)");
-    loop {
-        println!("Temperature (Fahrenheit): {:?}", climate.read_temperature());
-
-        // Continue the loop after sleeping for 100ms.
-        sleep(Duration::from_millis(100));
+    let mut scheduler = Scheduler::new();
+    let readings = scheduler.add("climate", climate, Duration::from_millis(100));
+    for reading in readings {
+        match reading {
+            Ok(reading) => {
+                let c = reading.value;
+                println!("Temperature: {}, humidity: {}", Fahrenheit::from(c.temperature), c.humidity);
+            }
+            Err(e) => println!("Could not read climate: {}", e),
+        }
     }
 }
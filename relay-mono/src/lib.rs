@@ -1,6 +1,7 @@
 //! http://cache.nxp.com/documents/data_sheet/PCA9685.pdf?pspll=1
 
 extern crate tessel;
+#[macro_use] extern crate tracing;
 
 use std::io;
 use std::thread;
@@ -28,6 +29,10 @@ impl<'a> RelayArray<'a> {
     }
 
     pub fn connect(&mut self) -> io::Result<()> {
+        // Open both relays if the program stops or the array is dropped.
+        self.pin1.set_safe_state(false);
+        self.pin2.set_safe_state(false);
+
         // Set GPIOs as outputs.
         self.pin1.output(false);
         self.pin2.output(false);
@@ -35,7 +40,20 @@ impl<'a> RelayArray<'a> {
         Ok(())
     }
 
+    /// Sets the state relay `index` (1 or 2) is switched to when the program
+    /// stops or the array is dropped. Relays are opened by default.
+    pub fn set_safe_state(&mut self, index: usize, value: bool) {
+        if index == 1 {
+            self.pin1.set_safe_state(value);
+        } else if index == 2 {
+            self.pin2.set_safe_state(value);
+        } else {
+            panic!("Invalid relay channel {:?}", index);
+        }
+    }
+
     pub fn set_latch(&mut self, index: usize, value: bool) {
+        debug!(relay = index, closed = value, "setting relay");
         if index == 1 {
             self.pin1.output(value);
             self.states[0] = value;
@@ -5,11 +5,14 @@ extern crate relay_mono;
 extern crate tessel;
 
 use relay_mono::RelayArray;
-use tessel::Tessel;
+use tessel::{shutdown, Tessel};
 use std::thread::sleep;
 use std::time::Duration;
 
 fn main() {
+    // Return outputs to a safe state on CTRL + C.
+    shutdown::handle_signals().unwrap();
+
     // Acquire port A.
     let (port_a, _) = Tessel::ports().unwrap();
 
@@ -1,6 +1,7 @@
 //! http://cache.nxp.com/documents/data_sheet/PCA9685.pdf?pspll=1
 
 extern crate tessel;
+#[macro_use] extern crate tracing;
 
 use std::io;
 use std::thread;
@@ -65,6 +66,9 @@ impl<'a> ServoArray<'a> {
     //}
 
     pub fn connect(&mut self) -> io::Result<()> {
+        // Disable the outputs if the program stops or the array is dropped.
+        self.output_enable.set_safe_state(true);
+
         // Enable the outputs.
         self.output_enable.output(false);
 
@@ -73,10 +77,7 @@ impl<'a> ServoArray<'a> {
         self.addr3.output(false);
 
         //let mut buf = [0; 6];
-        //println!("hi");
         //try!(self.read(&[Command::ReadId3, Command::ReadId4], &mut buf));
-        //println!("hey");
-        //println!("hi {:?}", buf);
         //if buf[0] != 0x14 {
         //    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid connection code."))
         //}
@@ -92,6 +93,7 @@ impl<'a> ServoArray<'a> {
         let mut buf = [0; 1];
         self.i2c.transfer(self.i2c_id, &[Command::MODE1 as u8], &mut buf);
         let mode = buf[0];
+        debug!(frequency, prescale, mode, "setting PWM frequency");
 
         self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, mode | 0x10]);
         self.i2c.send(self.i2c_id, &[Command::PRESCALE as u8, prescale]);
@@ -99,11 +101,25 @@ impl<'a> ServoArray<'a> {
         self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, 0xA1]);
     }
 
+    /// Sets the duty cycles that `set_position` maps positions 0 and 1 to,
+    /// calibrating the array for its servos' pulse widths.
+    pub fn set_range(&mut self, range: Range<f64>) {
+        self.range = range;
+    }
+
+    /// Moves servo `i` (1 to 16) to `position`, from 0 to 1 across the
+    /// calibrated range.
+    pub fn set_position(&mut self, i: usize, position: f64) {
+        let position = f64::max(f64::min(position, 1.0), 0.0);
+        let value = self.range.start + (self.range.end - self.range.start) * position;
+        self.set_duty_cycle(i, value);
+    }
+
     /// Set duty cycle for entry 1 to 16.
     pub fn set_duty_cycle(&mut self, i: usize, value: f64) {
         let offset = ((i - 1) * 4) as u8;
         let reg = (((MAX - 1) as f64) * f64::max(f64::min(value, 1.0), 0.0)) as u16;
-        println!("0 0 {:?} {:?}", (reg & 0xFF) as u8, ((reg >> 8) & 0xFF) as u8);
+        trace!(servo = i, value, register = reg, "setting duty cycle");
         self.i2c.send(self.i2c_id, &[Command::LED0_ON_L as u8 + offset, 0]);
         self.i2c.send(self.i2c_id, &[Command::LED0_ON_H as u8 + offset, 0]);
         self.i2c.send(self.i2c_id, &[Command::LED0_OFF_L as u8 + offset, (reg & 0xFF) as u8]);
@@ -2,11 +2,14 @@ extern crate servo_pca9685;
 extern crate tessel;
 
 use servo_pca9685::ServoArray;
-use tessel::Tessel;
+use tessel::{shutdown, Tessel};
 use std::thread::sleep;
 use std::time::Duration;
 
 fn main() {
+    // Return outputs to a safe state on CTRL + C.
+    shutdown::handle_signals().unwrap();
+
     // Acquire port A.
     let (port_a, _) = Tessel::ports().unwrap();
 
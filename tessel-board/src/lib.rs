@@ -0,0 +1,399 @@
+//! Constructs module drivers from a board manifest, so the modules on each
+//! port and their settings can be changed without recompiling.
+//!
+//! A manifest is a TOML file with a table per port naming the module's
+//! driver crate and its settings:
+//!
+//! ```toml
+//! [ports.a]
+//! module = "accel-mma84"
+//! range = 4     # g
+//! rate = 100    # Hz
+//!
+//! [ports.b]
+//! module = "relay-mono"
+//! safe_state_1 = true
+//! ```
+//!
+//! # Example
+//! ```rust,no_run
+//! extern crate accel_mma84;
+//! extern crate tessel_board;
+//!
+//! use accel_mma84::Accelerometer;
+//! use tessel_board::Board;
+//!
+//! fn main() {
+//!     let mut board = Board::load("/etc/tessel/board.toml").unwrap();
+//!     let mut accel: Accelerometer = board.take("a").expect("No accelerometer on port A.");
+//!     println!("{:?}", accel.read_acceleration());
+//! }
+//! ```
+
+extern crate accel_mma84;
+extern crate climate_si7020;
+extern crate relay_mono;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate servo_pca9685;
+extern crate tessel;
+extern crate toml;
+#[macro_use] extern crate tracing;
+
+use accel_mma84::{Accelerometer, SampleRate, ScaleRange};
+use climate_si7020::Climate;
+use relay_mono::RelayArray;
+use servo_pca9685::ServoArray;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use tessel::{Port, Tessel};
+use toml::Value;
+
+/// The modules on each port, keyed by port name ("a" or "b").
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub ports: BTreeMap<String, ModuleConfig>,
+}
+
+impl Manifest {
+    pub fn parse(source: &str) -> io::Result<Manifest> {
+        toml::from_str(source).map_err(|e| invalid(format!("Invalid board manifest: {}", e)))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Manifest> {
+        let mut source = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut source));
+        Manifest::parse(&source)
+    }
+}
+
+/// A module and its settings.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ModuleConfig {
+    /// The name of the module's driver crate, such as "accel-mma84".
+    pub module: String,
+    #[serde(flatten)]
+    settings: BTreeMap<String, Value>,
+}
+
+impl ModuleConfig {
+    /// Reads a number setting, accepting integers as well.
+    pub fn get_f64(&self, key: &str) -> io::Result<Option<f64>> {
+        match self.settings.get(key) {
+            None => Ok(None),
+            Some(&Value::Float(value)) => Ok(Some(value)),
+            Some(&Value::Integer(value)) => Ok(Some(value as f64)),
+            Some(_) => Err(self.mistyped(key, "a number")),
+        }
+    }
+
+    pub fn get_u64(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.settings.get(key) {
+            None => Ok(None),
+            Some(&Value::Integer(value)) if value >= 0 => Ok(Some(value as u64)),
+            Some(_) => Err(self.mistyped(key, "a positive integer")),
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> io::Result<Option<bool>> {
+        match self.settings.get(key) {
+            None => Ok(None),
+            Some(&Value::Boolean(value)) => Ok(Some(value)),
+            Some(_) => Err(self.mistyped(key, "true or false")),
+        }
+    }
+
+    fn mistyped(&self, key: &str, expected: &str) -> io::Error {
+        invalid(format!("Setting `{}` of {} must be {}.", key, self.module, expected))
+    }
+}
+
+type Constructor = Box<Fn(Port, &ModuleConfig) -> io::Result<Box<Any>>>;
+
+/// Maps module names to functions constructing their drivers.
+pub struct Registry {
+    constructors: BTreeMap<String, Constructor>,
+}
+
+impl Registry {
+    /// Creates a registry with no drivers. `Registry::default()` has the
+    /// drivers in this repository.
+    pub fn new() -> Registry {
+        Registry {
+            constructors: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the constructor for a module's driver, replacing any
+    /// earlier one. The constructor should leave the driver connected.
+    pub fn register<D, F>(&mut self, module: &str, constructor: F)
+        where D: Any, F: Fn(Port, &ModuleConfig) -> io::Result<D> + 'static
+    {
+        let constructor: Constructor = Box::new(move |port, config| {
+            constructor(port, config).map(|driver| Box::new(driver) as Box<Any>)
+        });
+        self.constructors.insert(module.to_string(), constructor);
+    }
+
+    /// Constructs the driver for each port in the manifest.
+    pub fn build(&self, manifest: &Manifest, ports: (Port, Port)) -> io::Result<Board> {
+        let mut free = BTreeMap::new();
+        free.insert("a".to_string(), ports.0);
+        free.insert("b".to_string(), ports.1);
+
+        let mut drivers = BTreeMap::new();
+        for (name, config) in &manifest.ports {
+            let port = try!(free.remove(name).ok_or_else(|| invalid(format!("Unknown port `{}`.", name))));
+            let constructor = try!(self.constructors.get(&config.module).ok_or_else(|| {
+                invalid(format!("No driver registered for module `{}`.", config.module))
+            }));
+            debug!(port = %name, module = %config.module, "constructing driver");
+            let driver = try!(constructor(port, config));
+            drivers.insert(name.clone(), (config.module.clone(), driver));
+        }
+
+        Ok(Board {
+            drivers: drivers,
+            ports: free,
+        })
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        registry.register("accel-mma84", accel);
+        registry.register("climate-si7020", climate);
+        registry.register("relay-mono", relay);
+        registry.register("servo-pca9685", servo);
+        registry
+    }
+}
+
+/// The drivers constructed from a manifest.
+pub struct Board {
+    drivers: BTreeMap<String, (String, Box<Any>)>,
+    ports: BTreeMap<String, Port>,
+}
+
+impl Board {
+    /// Constructs the drivers in the manifest at `path` on this Tessel's
+    /// ports, using the default registry.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Board> {
+        let manifest = try!(Manifest::load(path));
+        let ports = try!(Tessel::ports().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Could not open the module ports.")
+        }));
+        Registry::default().build(&manifest, ports)
+    }
+
+    /// The module on a port, if the manifest names one.
+    pub fn module(&self, port: &str) -> Option<&str> {
+        self.drivers.get(port).map(|&(ref module, _)| &module[..])
+    }
+
+    /// Takes the driver on a port. Returns `None` if there is none or it is
+    /// not a `D`.
+    pub fn take<D: Any>(&mut self, port: &str) -> Option<D> {
+        let (module, driver) = match self.drivers.remove(port) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        match driver.downcast::<D>() {
+            Ok(driver) => Some(*driver),
+            Err(driver) => {
+                self.drivers.insert(port.to_string(), (module, driver));
+                None
+            }
+        }
+    }
+
+    /// Takes a port the manifest did not assign a module to.
+    pub fn take_port(&mut self, port: &str) -> Option<Port> {
+        self.ports.remove(port)
+    }
+}
+
+fn invalid<E: Into<Box<std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// Settings: `range` in g (2, 4 or 8) and `rate` in Hz (800 down to 1.56).
+fn accel(port: Port, config: &ModuleConfig) -> io::Result<Accelerometer<'static>> {
+    let mut accel = Accelerometer::new(port);
+    try!(accel.connect());
+    if let Some(range) = try!(config.get_u64("range")) {
+        let range = match range {
+            2 => ScaleRange::Scale2G,
+            4 => ScaleRange::Scale4G,
+            8 => ScaleRange::Scale8G,
+            _ => return Err(config.mistyped("range", "2, 4 or 8")),
+        };
+        try!(accel.set_scale_range(range));
+    }
+    if let Some(rate) = try!(config.get_f64("rate")) {
+        let rate = match (rate * 100.0).round() as u64 {
+            80000 => SampleRate::Rate800,
+            40000 => SampleRate::Rate400,
+            20000 => SampleRate::Rate200,
+            10000 => SampleRate::Rate100,
+            5000 => SampleRate::Rate50,
+            1250 => SampleRate::Rate12_5,
+            625 => SampleRate::Rate6_25,
+            156 => SampleRate::Rate1_56,
+            _ => return Err(config.mistyped("rate", "800, 400, 200, 100, 50, 12.5, 6.25 or 1.56")),
+        };
+        try!(accel.set_sample_rate(rate));
+    }
+    Ok(accel)
+}
+
+fn climate(port: Port, _: &ModuleConfig) -> io::Result<Climate<'static>> {
+    let mut climate = Climate::new(port);
+    try!(climate.connect());
+    Ok(climate)
+}
+
+// Settings: `safe_state_1` and `safe_state_2`, true to close the relay
+// when the program stops.
+fn relay(port: Port, config: &ModuleConfig) -> io::Result<RelayArray<'static>> {
+    let mut relays = RelayArray::new(port);
+    try!(relays.connect());
+    for index in 1..3 {
+        if let Some(closed) = try!(config.get_bool(&format!("safe_state_{}", index))) {
+            relays.set_safe_state(index, closed);
+        }
+    }
+    Ok(relays)
+}
+
+// Settings: the `addr2` and `addr3` address pins, the PWM `frequency` in Hz
+// and the `min_duty` and `max_duty` cycles that positions 0 and 1 map to.
+fn servo(port: Port, config: &ModuleConfig) -> io::Result<ServoArray<'static>> {
+    let addr2 = try!(config.get_bool("addr2")).unwrap_or(false);
+    let addr3 = try!(config.get_bool("addr3")).unwrap_or(false);
+    // The PCA9685's prescaler can only generate 24 to 1526 Hz.
+    let frequency = try!(config.get_u64("frequency"));
+    if frequency.map_or(false, |frequency| frequency < 24 || frequency > 1526) {
+        return Err(config.mistyped("frequency", "between 24 and 1526"));
+    }
+    let min = try!(config.get_f64("min_duty")).unwrap_or(0.0);
+    let max = try!(config.get_f64("max_duty")).unwrap_or(1.0);
+    if !(0.0 <= min && min <= max && max <= 1.0) {
+        return Err(invalid(format!("Settings `min_duty` and `max_duty` of {} must be in order between 0 and 1.",
+                                   config.module)));
+    }
+
+    let mut servos = ServoArray::new(port, addr2, addr3);
+    try!(servos.connect());
+    if let Some(frequency) = frequency {
+        servos.set_module_frequency(frequency);
+    }
+    servos.set_range(min..max);
+    Ok(servos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tessel::transport;
+    use tessel::transport::Transport;
+
+    const MANIFEST: &'static str = r#"
+        [ports.a]
+        module = "counter"
+        start = 3
+        step = 0.5
+
+        [ports.b]
+        module = "flag"
+        set = "yes"
+    "#;
+
+    struct Counter(f64, f64);
+
+    fn ports() -> (Port, Port) {
+        (Port::with_transport(transport::pipe().0), Port::with_transport(transport::pipe().0))
+    }
+
+    #[test]
+    fn parses_module_settings() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        let a = &manifest.ports["a"];
+        assert_eq!("counter", a.module);
+        assert_eq!(Some(3), a.get_u64("start").unwrap());
+        assert_eq!(Some(3.0), a.get_f64("start").unwrap());
+        assert_eq!(Some(0.5), a.get_f64("step").unwrap());
+        assert_eq!(None, a.get_bool("missing").unwrap());
+        assert!(a.get_u64("step").is_err());
+        assert!(manifest.ports["b"].get_bool("set").is_err());
+
+        assert!(Manifest::parse("[ports.a]\nrange = 2").is_err());
+    }
+
+    #[test]
+    fn builds_registered_drivers() {
+        let mut registry = Registry::new();
+        registry.register("counter", |_, config| {
+            Ok(Counter(try!(config.get_f64("start")).unwrap_or(0.0),
+                       try!(config.get_f64("step")).unwrap_or(1.0)))
+        });
+
+        let manifest = Manifest::parse("[ports.a]\nmodule = \"counter\"\nstart = 3\nstep = 0.5").unwrap();
+        let mut board = registry.build(&manifest, ports()).unwrap();
+        assert_eq!(Some("counter"), board.module("a"));
+        assert!(board.take::<bool>("a").is_none());
+        let counter: Counter = board.take("a").unwrap();
+        assert_eq!((3.0, 0.5), (counter.0, counter.1));
+        assert!(board.take::<Counter>("a").is_none());
+        assert!(board.take_port("a").is_none());
+        assert!(board.take_port("b").is_some());
+
+        // Every module needs a registered driver.
+        assert!(registry.build(&Manifest::parse(MANIFEST).unwrap(), ports()).is_err());
+        let manifest = Manifest::parse("[ports.c]\nmodule = \"counter\"").unwrap();
+        assert!(registry.build(&manifest, ports()).is_err());
+    }
+
+    #[test]
+    fn builds_default_drivers() {
+        let registry = Registry::default();
+        let (local, mut remote) = transport::pipe();
+        let wired = (Port::with_transport(transport::pipe().0), Port::with_transport(local));
+        let manifest = Manifest::parse("[ports.b]\nmodule = \"relay-mono\"\nsafe_state_1 = true").unwrap();
+        let mut board = registry.build(&manifest, wired).unwrap();
+        assert_eq!(Some("relay-mono"), board.module("b"));
+        let mut relays: RelayArray = board.take("b").unwrap();
+
+        // Connecting drove both relay pins low; closing relay 1 drives it high.
+        let mut sent = Vec::new();
+        remote.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let _ = remote.read_to_end(&mut sent);
+        relays.set_latch(1, true);
+        let mut latch = [0; 2];
+        remote.read_exact(&mut latch).unwrap();
+        assert_eq!([0x04, 5], latch);
+
+        let (local, _remote) = transport::pipe();
+        let wired = (Port::with_transport(transport::pipe().0), Port::with_transport(local));
+        let manifest = Manifest::parse("[ports.b]\nmodule = \"relay-mono\"\nsafe_state_1 = 1").unwrap();
+        assert!(registry.build(&manifest, wired).is_err());
+    }
+
+    #[test]
+    fn rejects_servo_settings_out_of_range() {
+        let registry = Registry::default();
+        for settings in &["frequency = 0", "frequency = 2000", "min_duty = -0.1",
+                          "max_duty = 1.5", "min_duty = 0.5\nmax_duty = 0.2"] {
+            let source = format!("[ports.a]\nmodule = \"servo-pca9685\"\n{}", settings);
+            let manifest = Manifest::parse(&source).unwrap();
+            assert!(registry.build(&manifest, ports()).is_err(), "{}", settings);
+        }
+    }
+}
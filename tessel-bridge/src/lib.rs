@@ -0,0 +1,175 @@
+//! Forwards a Tessel's module port sockets over authenticated TCP.
+//!
+//! Run the `tessel-bridge` binary on the Tessel, then set `TESSEL_BRIDGE`
+//! and `TESSEL_BRIDGE_TOKEN` on a development machine so that
+//! `Tessel::ports()` drives the remote hardware. See `tessel::bridge` for
+//! the wire handshake.
+
+extern crate tessel;
+#[macro_use] extern crate tracing;
+extern crate unix_socket;
+
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tessel::bridge::{self, PortId};
+use unix_socket::UnixStream;
+
+// Paths to the SPI daemon sockets with incoming data from coprocessor.
+const PORT_A_UDS_PATH: &'static str = "/var/run/tessel/port_a";
+const PORT_B_UDS_PATH: &'static str = "/var/run/tessel/port_b";
+/// How long a client has to complete the handshake by default.
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// Accepts bridge clients and connects them to the local port sockets.
+#[derive(Clone)]
+pub struct Bridge {
+    token: String,
+    port_a_path: String,
+    port_b_path: String,
+    handshake_timeout: Duration,
+}
+
+impl Bridge {
+    /// Creates a bridge for the Tessel's own port sockets.
+    pub fn new(token: &str) -> Bridge {
+        Bridge::with_paths(token, PORT_A_UDS_PATH, PORT_B_UDS_PATH)
+    }
+
+    /// Creates a bridge for port sockets at custom paths.
+    pub fn with_paths(token: &str, port_a_path: &str, port_b_path: &str) -> Bridge {
+        Bridge {
+            token: token.to_string(),
+            port_a_path: port_a_path.to_string(),
+            port_b_path: port_b_path.to_string(),
+            handshake_timeout: Duration::from_millis(HANDSHAKE_TIMEOUT_MS),
+        }
+    }
+
+    /// Sets how long a client has to complete the handshake before it is
+    /// disconnected, so idle connections can't hold threads forever.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Serves clients from `listener` forever, one thread per connection.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            let bridge = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(e) = bridge.handle(stream) {
+                    info!(peer = ?peer, error = %e, "bridge client disconnected");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Authenticates a single client and forwards its traffic until either
+    /// side closes the connection.
+    pub fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        try!(stream.set_read_timeout(Some(self.handshake_timeout)));
+        let port = try!(bridge::accept_request(&mut stream, &self.token));
+        // Authenticated clients may sit idle between commands.
+        try!(stream.set_read_timeout(None));
+        try!(stream.set_nodelay(true));
+        let path = match port {
+            PortId::A => &self.port_a_path,
+            PortId::B => &self.port_b_path,
+        };
+        let socket = try!(UnixStream::connect(path));
+
+        let mut tcp_in = try!(stream.try_clone());
+        let mut socket_out = try!(socket.try_clone());
+        let upstream = thread::spawn(move || {
+            let result = io::copy(&mut tcp_in, &mut socket_out);
+            // Let the port socket know the client has gone away.
+            let _ = socket_out.shutdown(Shutdown::Write);
+            result
+        });
+
+        let mut socket_in = socket;
+        let mut tcp_out = stream;
+        let result = io::copy(&mut socket_in, &mut tcp_out);
+        let _ = tcp_out.shutdown(Shutdown::Both);
+
+        try!(upstream.join().unwrap());
+        try!(result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use tessel::bridge::{self, PortId};
+    use unix_socket::UnixListener;
+
+    #[test]
+    fn forwards_over_loopback() {
+        let dir = tempfile::tempdir().unwrap();
+        let port_b_path = dir.path().join("port_b");
+        let port_b_path = port_b_path.to_str().unwrap();
+        let spid = UnixListener::bind(port_b_path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let bridge = Bridge::with_paths("secret", "/nonexistent", port_b_path);
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            bridge.handle(stream).unwrap();
+        });
+
+        let mut client = bridge::connect(addr, PortId::B, "secret").unwrap();
+        let (mut coprocessor, _) = spid.accept().unwrap();
+
+        client.write_all(&[0x02, 0x01, 0x55]).unwrap();
+        let mut buf = [0; 3];
+        coprocessor.read_exact(&mut buf).unwrap();
+        assert_eq!([0x02, 0x01, 0x55], buf);
+
+        coprocessor.write_all(&[0x84, 0x55]).unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!([0x84, 0x55], buf);
+    }
+
+    #[test]
+    fn rejects_bad_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let bridge = Bridge::with_paths("secret", "/nonexistent", "/nonexistent");
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            bridge.handle(stream)
+        });
+
+        let err = bridge::connect(addr, PortId::A, "wrong").unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn drops_silent_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut bridge = Bridge::with_paths("secret", "/nonexistent", "/nonexistent");
+        bridge.set_handshake_timeout(Duration::from_millis(10));
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            bridge.handle(stream)
+        });
+
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+}
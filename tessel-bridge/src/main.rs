@@ -0,0 +1,20 @@
+extern crate tessel;
+extern crate tessel_bridge;
+
+use std::env;
+use std::net::TcpListener;
+use tessel::bridge;
+use tessel_bridge::Bridge;
+
+fn main() {
+    // Listen on the given address, or on every interface by default.
+    let addr = env::args().nth(1)
+        .unwrap_or_else(|| format!("0.0.0.0:{}", bridge::DEFAULT_PORT));
+    let token = env::var(bridge::TOKEN_VAR)
+        .expect("Set TESSEL_BRIDGE_TOKEN to the token clients must present.");
+
+    let listener = TcpListener::bind(&*addr).expect("Could not bind bridge address.");
+    println!("Bridging ports A and B on {}... (Press CTRL + C to stop)", addr);
+
+    Bridge::new(&token).serve(listener).unwrap();
+}
@@ -0,0 +1,406 @@
+//! Shares one module port socket between several client processes.
+//!
+//! Clients connect with `tessel::broker::BrokerStream`. Each transaction a
+//! client begins is granted the port exclusively, in the order requested,
+//! and the port's replies go only to that client. Data sent outside a
+//! transaction holds the port until the replies its commands expect have
+//! come back. Anything else the coprocessor sends while no transaction is
+//! running is an asynchronous event and is copied to every subscriber.
+//! A client that stops reading is disconnected once a write to it has been
+//! blocked for a second, so it can't hold up the port for everyone else.
+
+extern crate tessel;
+#[macro_use] extern crate tracing;
+extern crate unix_socket;
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tessel::broker::{frame, read_frame, write_frame};
+use tessel::protocol::{self, reply};
+use unix_socket::{UnixListener, UnixStream};
+
+// Longest the port is held for the replies to data sent outside a
+// transaction, in case the coprocessor never answers.
+const REPLY_TIMEOUT_MS: u64 = 2000;
+// Longest a write to a client may block before the client is disconnected.
+const WRITE_TIMEOUT_MS: u64 = 1000;
+
+// The write half of a client's connection. Frames are written with the
+// lock held so they can't interleave.
+type Client = Arc<Mutex<UnixStream>>;
+
+/// Multiplexes clients onto a single port socket.
+#[derive(Clone)]
+pub struct Broker {
+    port: Arc<Mutex<UnixStream>>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    // Client currently holding the port, if any.
+    owner: Option<usize>,
+    // Clients waiting for the port, in the order they asked.
+    queue: VecDeque<usize>,
+    clients: HashMap<usize, Client>,
+    // Clients that asked for events.
+    subscribers: Vec<usize>,
+    next_id: usize,
+    // Lengths of the replies still owed to data sent outside a transaction,
+    // and how much of the first one has arrived.
+    expected: VecDeque<usize>,
+    received: usize,
+}
+
+impl Broker {
+    /// Connects to the port socket at `path` and starts routing its output.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Broker> {
+        let port = try!(UnixStream::connect(path));
+        let reader = try!(port.try_clone());
+
+        let broker = Broker {
+            port: Arc::new(Mutex::new(port)),
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    owner: None,
+                    queue: VecDeque::new(),
+                    clients: HashMap::new(),
+                    subscribers: vec![],
+                    next_id: 0,
+                    expected: VecDeque::new(),
+                    received: 0,
+                }),
+                released: Condvar::new(),
+            }),
+        };
+
+        let shared = broker.shared.clone();
+        thread::spawn(move || Broker::route(reader, shared));
+        Ok(broker)
+    }
+
+    /// Serves clients from `listener` forever, one thread per connection.
+    pub fn serve(&self, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            let broker = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = broker.handle(stream) {
+                    info!(error = %e, "broker client disconnected");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Handles a single client until it disconnects.
+    pub fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        let writer = try!(stream.try_clone());
+        try!(writer.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS))));
+        let id = {
+            let mut state = self.lock();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.clients.insert(id, Arc::new(Mutex::new(writer)));
+            id
+        };
+
+        let result = self.serve_client(id, stream);
+
+        // Never leave the port locked by a client that has gone away.
+        self.release(id);
+        {
+            let mut state = self.lock();
+            state.clients.remove(&id);
+            state.subscribers.retain(|&subscriber| subscriber != id);
+        }
+        result
+    }
+
+    fn serve_client(&self, id: usize, mut stream: UnixStream) -> io::Result<()> {
+        loop {
+            let (kind, payload) = match read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            match kind {
+                frame::BEGIN => try!(self.acquire(id)),
+                frame::DATA => {
+                    if self.lock().owner == Some(id) {
+                        try!(self.port.lock().unwrap().write_all(&payload));
+                    } else {
+                        try!(self.send_alone(id, &payload));
+                    }
+                }
+                frame::END => self.release(id),
+                frame::SUBSCRIBE => {
+                    // Acknowledge before registering, so the acknowledgement
+                    // comes before any event.
+                    let mut state = self.lock();
+                    if let Some(client) = state.clients.get(&id) {
+                        try!(write_frame(&mut *client.lock().unwrap(), frame::SUBSCRIBE, &[]));
+                    }
+                    state.subscribers.push(id);
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown broker frame.")),
+            }
+        }
+    }
+
+    // Data sent outside a transaction is its own transaction, which lasts
+    // until the replies its commands expect have been routed back.
+    fn send_alone(&self, id: usize, payload: &[u8]) -> io::Result<()> {
+        // Unparsable data can't be waited on; treat it as expecting nothing.
+        let expected = protocol::reply_lengths(payload).unwrap_or_default();
+        try!(self.acquire(id));
+        self.lock().expected = expected.into_iter().collect();
+
+        let result = self.port.lock().unwrap().write_all(payload);
+        if result.is_ok() {
+            let deadline = Instant::now() + Duration::from_millis(REPLY_TIMEOUT_MS);
+            let mut state = self.lock();
+            while !state.expected.is_empty() {
+                let now = Instant::now();
+                if now >= deadline {
+                    warn!(client = id, "coprocessor did not answer, releasing the port");
+                    break;
+                }
+                state = self.shared.released.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+        {
+            let mut state = self.lock();
+            state.expected.clear();
+            state.received = 0;
+        }
+        self.release(id);
+        result
+    }
+
+    // Waits for the client's turn, then grants it the port.
+    fn acquire(&self, id: usize) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.owner == Some(id) {
+            return Ok(());
+        }
+        state.queue.push_back(id);
+        while state.owner.is_some() || state.queue.front() != Some(&id) {
+            state = self.shared.released.wait(state).unwrap();
+        }
+        state.queue.pop_front();
+        state.owner = Some(id);
+
+        // Answer while still holding the lock so the grant is sent before
+        // any reply data routed to this client.
+        match state.clients.get(&id) {
+            Some(client) => write_frame(&mut *client.lock().unwrap(), frame::BEGIN, &[]),
+            None => Ok(()),
+        }
+    }
+
+    fn release(&self, id: usize) {
+        let mut state = self.lock();
+        if state.owner == Some(id) {
+            state.owner = None;
+            self.shared.released.notify_all();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    // Sends everything read from the port to its owner, or to subscribers
+    // when no transaction is running. Clients are written to after the
+    // state is unlocked, so a slow one holds up only the routing.
+    fn route(mut port: UnixStream, shared: Arc<Shared>) {
+        let mut buf = [0; 256];
+        loop {
+            let len = match port.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+
+            let data = &buf[..len];
+            let mut deliveries = vec![];
+            {
+                let mut state = shared.state.lock().unwrap();
+                match state.owner {
+                    Some(id) if !state.expected.is_empty() => {
+                        let (replies, events) = data.split_at(state.take_replies(data));
+                        deliveries.extend(state.client(id, frame::DATA, replies));
+                        if state.expected.is_empty() {
+                            shared.released.notify_all();
+                            deliveries.extend(state.broadcast(events));
+                        }
+                    }
+                    Some(id) => deliveries.extend(state.client(id, frame::DATA, data)),
+                    None => deliveries.extend(state.broadcast(data)),
+                }
+            }
+
+            for (id, client, kind, data) in deliveries {
+                let mut stream = client.lock().unwrap();
+                if let Err(error) = write_frame(&mut *stream, kind, data) {
+                    // Its handler cleans up once it sees the connection close.
+                    warn!(client = id, %error, "disconnecting a client that is not reading");
+                    let _ = stream.shutdown(Shutdown::Both);
+                    shared.state.lock().unwrap().subscribers.retain(|&subscriber| subscriber != id);
+                }
+            }
+        }
+    }
+}
+
+// A frame to write to a client once the state is unlocked.
+type Delivery<'a> = (usize, Client, u8, &'a [u8]);
+
+impl State {
+    // Counts `data` against the expected replies, returning how many of its
+    // bytes belong to them.
+    fn take_replies(&mut self, data: &[u8]) -> usize {
+        let mut taken = 0;
+        while let Some(&len) = self.expected.front() {
+            if taken == data.len() {
+                break;
+            }
+            // A NACK is the whole reply.
+            let nack = self.received == 0 && data[taken] == reply::NACK.0;
+            let wanted = if nack { 1 } else { len - self.received };
+            let available = wanted.min(data.len() - taken);
+            taken += available;
+            self.received += available;
+            if available == wanted {
+                self.expected.pop_front();
+                self.received = 0;
+            }
+        }
+        taken
+    }
+
+    fn client<'a>(&self, id: usize, kind: u8, data: &'a [u8]) -> Option<Delivery<'a>> {
+        self.clients.get(&id).map(|client| (id, client.clone(), kind, data))
+    }
+
+    fn broadcast<'a>(&self, data: &'a [u8]) -> Vec<Delivery<'a>> {
+        if data.is_empty() {
+            return vec![];
+        }
+        self.subscribers.iter().filter_map(|&id| self.client(id, frame::EVENT, data)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::thread;
+    use tessel::broker::{self, BrokerStream};
+    use tessel::transport::Transport;
+    use unix_socket::{UnixListener, UnixStream};
+    use self::tempfile::TempDir;
+
+    // Starts a broker in front of a fake spid socket, both in a directory
+    // that lives as long as the returned `TempDir`.
+    fn start() -> (TempDir, String, UnixStream) {
+        let dir = tempfile::tempdir().unwrap();
+        let spid_path = dir.path().join("spid");
+        let broker_path = dir.path().join("broker");
+        let spid = UnixListener::bind(&spid_path).unwrap();
+        let listener = UnixListener::bind(&broker_path).unwrap();
+
+        let broker = Broker::connect(&spid_path).unwrap();
+        let (coprocessor, _) = spid.accept().unwrap();
+        thread::spawn(move || broker.serve(listener));
+        (dir, broker_path.to_str().unwrap().to_string(), coprocessor)
+    }
+
+    #[test]
+    fn transactions_are_not_interleaved() {
+        let (_dir, path, mut coprocessor) = start();
+        let mut first = BrokerStream::connect(&path).unwrap();
+        let mut second = BrokerStream::connect(&path).unwrap();
+
+        first.begin_transaction().unwrap();
+        first.write_all(&[0x13, 0x3A]).unwrap();
+        let waiter = thread::spawn(move || {
+            second.begin_transaction().unwrap();
+            second.write_all(&[0x14]).unwrap();
+            second.end_transaction().unwrap();
+        });
+
+        // Replies go to the transaction's owner.
+        coprocessor.write_all(&[0x84, 0x2A]).unwrap();
+        let mut reply = [0; 2];
+        first.read_exact(&mut reply).unwrap();
+        assert_eq!([0x84, 0x2A], reply);
+
+        first.write_all(&[0x11, 0x01]).unwrap();
+        first.end_transaction().unwrap();
+        waiter.join().unwrap();
+
+        let mut sent = [0; 5];
+        coprocessor.read_exact(&mut sent).unwrap();
+        assert_eq!([0x13, 0x3A, 0x11, 0x01, 0x14], sent);
+    }
+
+    #[test]
+    fn events_fan_out_to_subscribers() {
+        let (_dir, path, mut coprocessor) = start();
+        let mut first = broker::subscribe(&path).unwrap();
+        let mut second = broker::subscribe(&path).unwrap();
+
+        coprocessor.write_all(&[0xC2]).unwrap();
+        assert_eq!(vec![0xC2], first.next().unwrap().unwrap());
+        assert_eq!(vec![0xC2], second.next().unwrap().unwrap());
+    }
+
+    #[test]
+    fn subscriber_that_stops_reading_is_dropped() {
+        let (_dir, path, coprocessor) = start();
+        let _stalled = broker::subscribe(&path).unwrap();
+        let mut events = broker::subscribe(&path).unwrap();
+
+        // Far more events than the stalled subscriber's socket can buffer.
+        const LEN: usize = 4 * 1024 * 1024;
+        let mut flood = coprocessor.try_clone().unwrap();
+        let flooder = thread::spawn(move || flood.write_all(&vec![0xC2; LEN]).unwrap());
+        let mut received = 0;
+        while received < LEN {
+            received += events.next().unwrap().unwrap().len();
+        }
+        flooder.join().unwrap();
+    }
+
+    #[test]
+    fn replies_to_data_outside_a_transaction_go_to_the_sender() {
+        let (_dir, path, mut coprocessor) = start();
+        let mut events = broker::subscribe(&path).unwrap();
+        let mut client = BrokerStream::connect(&path).unwrap();
+
+        // GPIO_IN 2, then ECHO [0x55].
+        client.write_all(&[0x03, 0x02, 0x02, 0x01, 0x55]).unwrap();
+        let mut sent = [0; 5];
+        coprocessor.read_exact(&mut sent).unwrap();
+
+        // The replies may arrive split up, followed by an event.
+        coprocessor.write_all(&[0x82, 0x84]).unwrap();
+        coprocessor.write_all(&[0x55, 0xC2]).unwrap();
+        let mut replies = [0; 3];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!([0x82, 0x84, 0x55], replies);
+        assert_eq!(vec![0xC2], events.next().unwrap().unwrap());
+    }
+}
@@ -0,0 +1,32 @@
+extern crate tessel;
+extern crate tessel_broker;
+extern crate unix_socket;
+
+use std::fs;
+use std::thread;
+use tessel::broker;
+use tessel_broker::Broker;
+use unix_socket::UnixListener;
+
+// Paths to the SPI daemon sockets with incoming data from coprocessor.
+const PORT_A_UDS_PATH: &'static str = "/var/run/tessel/port_a";
+const PORT_B_UDS_PATH: &'static str = "/var/run/tessel/port_b";
+
+fn main() {
+    let ports = [(PORT_A_UDS_PATH, broker::PORT_A_PATH), (PORT_B_UDS_PATH, broker::PORT_B_PATH)];
+
+    let threads: Vec<_> = ports.iter().map(|&(port_path, broker_path)| {
+        let broker = Broker::connect(port_path).expect("Could not connect to port socket.");
+
+        // Remove a socket left behind by a previous run.
+        let _ = fs::remove_file(broker_path);
+        let listener = UnixListener::bind(broker_path).expect("Could not bind broker socket.");
+        println!("Brokering {} on {}", port_path, broker_path);
+
+        thread::spawn(move || broker.serve(listener).unwrap())
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+//! Records sensor readings to CSV or JSON-lines files.
+//!
+//! Files are named `<prefix>-<unix time>-<sequence>.<csv|jsonl>` and a new
+//! one is started once the current file reaches its size or age limit.
+//! Whenever a file is started, the oldest files are deleted until the total
+//! is within the disk cap, so a logger can run indefinitely on the T2's
+//! flash or a USB stick. Writes are buffered and flushed periodically, when
+//! the logger is dropped and, with `tessel::shutdown::handle_signals`, when
+//! the process is interrupted.
+//!
+//! CSV files have one row per value:
+//!
+//! ```text
+//! timestamp,port,module,quantity,value,unit
+//! 1700000000.250,a,accel-mma84,x,0.012,g
+//! ```
+//!
+//! JSON-lines files have one object per reading:
+//!
+//! ```text
+//! {"timestamp":1700000000.25,"port":"a","module":"accel-mma84","values":[{"name":"x","value":0.012,"unit":"g"}]}
+//! ```
+//!
+//! # Example
+//! ```rust,no_run
+//! extern crate tessel;
+//! extern crate tessel_logger;
+//!
+//! use tessel::sensor::{Celsius, Reading};
+//! use tessel_logger::{Config, Logger};
+//! use std::time::SystemTime;
+//!
+//! fn main() {
+//!     let logger = Logger::new(Config::default()).unwrap();
+//!     let reading = Reading { timestamp: SystemTime::now(), value: Celsius(21.5) };
+//!     logger.log("b", "climate-si7020", &reading).unwrap();
+//! }
+//! ```
+
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate tessel;
+#[macro_use] extern crate tracing;
+
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tessel::sensor::{Measurement, Reading, Value};
+use tessel::shutdown::{self, SafeState};
+
+const CSV_HEADER: &'static str = "timestamp,port,module,quantity,value,unit\n";
+
+/// How readings are written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub enum Format {
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "jsonl")]
+    JsonLines,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// Where and how to write log files.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub directory: PathBuf,
+    /// Start of each file's name.
+    pub prefix: String,
+    pub format: Format,
+    /// Size at which a new file is started.
+    pub max_file_bytes: u64,
+    /// Age at which a new file is started, if any.
+    pub max_file_age_secs: Option<u64>,
+    /// Total size of all log files. The oldest are deleted to leave room
+    /// for a full current file, which is never deleted.
+    pub max_total_bytes: u64,
+    /// How often buffered readings are written out.
+    pub flush_interval_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            directory: PathBuf::from("/mnt/sda1/tessel-logger"),
+            prefix: "readings".to_string(),
+            format: Format::Csv,
+            max_file_bytes: 1024 * 1024,
+            max_file_age_secs: Some(24 * 60 * 60),
+            max_total_bytes: 16 * 1024 * 1024,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+/// Writes readings to rotating log files. Can be shared between threads.
+pub struct Logger {
+    inner: Arc<Mutex<Inner>>,
+    _flush_on_exit: SafeState,
+}
+
+struct Inner {
+    config: Config,
+    output: Option<Output>,
+    last_flush: Instant,
+    // Unix time and sequence number in the newest file's name.
+    last_name: Option<(u64, u32)>,
+}
+
+struct Output {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+}
+
+impl Logger {
+    /// Creates the log directory if needed. The first file is started by
+    /// the first reading.
+    pub fn new(config: Config) -> io::Result<Logger> {
+        try!(fs::create_dir_all(&config.directory));
+        let inner = Arc::new(Mutex::new(Inner {
+            config: config,
+            output: None,
+            last_flush: Instant::now(),
+            last_name: None,
+        }));
+        let flush = inner.clone();
+        Ok(Logger {
+            inner: inner,
+            _flush_on_exit: shutdown::register(move || {
+                let lock_timeout = Duration::from_millis(shutdown::LOCK_TIMEOUT_MS);
+                if let Some(mut inner) = shutdown::try_lock_for(&flush, lock_timeout) {
+                    if let Err(error) = inner.flush() {
+                        error!(%error, "could not flush log file");
+                    }
+                }
+            }),
+        })
+    }
+
+    /// Records a reading taken by `module` on `port`.
+    pub fn log<M: Measurement>(&self, port: &str, module: &str, reading: &Reading<M>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let record = match inner.config.format {
+            Format::Csv => csv(port, module, reading),
+            Format::JsonLines => try!(json(port, module, reading)),
+        };
+        inner.write(record.as_bytes())
+    }
+
+    /// Writes out buffered readings and syncs the current file to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+
+    /// The log files in the directory, oldest first.
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        self.inner.lock().unwrap().files()
+    }
+}
+
+impl Inner {
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.should_rotate(record.len() as u64) {
+            try!(self.rotate());
+        }
+        {
+            let output = self.output.as_mut().unwrap();
+            try!(output.writer.write_all(record));
+            output.bytes += record.len() as u64;
+        }
+        if self.last_flush.elapsed() >= Duration::from_millis(self.config.flush_interval_ms) {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    fn should_rotate(&self, len: u64) -> bool {
+        match self.output {
+            None => true,
+            Some(ref output) => {
+                let too_big = output.bytes + len > self.config.max_file_bytes;
+                let too_old = self.config.max_file_age_secs.map_or(false, |secs| {
+                    output.opened.elapsed() >= Duration::from_secs(secs)
+                });
+                too_big || too_old
+            }
+        }
+    }
+
+    // Closes the current file, starts the next one and deletes the oldest
+    // files over the cap.
+    fn rotate(&mut self) -> io::Result<()> {
+        try!(self.flush());
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        // Never reuse the name of a deleted file, which would sort as the oldest.
+        let mut sequence = match self.last_name {
+            Some((last_secs, last_sequence)) if last_secs == secs => last_sequence + 1,
+            _ => 0,
+        };
+        let (path, file) = loop {
+            let name = format!("{}-{:010}-{:04}.{}", self.config.prefix, secs, sequence,
+                               self.config.format.extension());
+            let path = self.config.directory.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => sequence += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        info!(path = %path.display(), "starting log file");
+        self.last_name = Some((secs, sequence));
+
+        let mut output = Output {
+            path: path,
+            writer: BufWriter::new(file),
+            bytes: 0,
+            opened: Instant::now(),
+        };
+        if self.config.format == Format::Csv {
+            try!(output.writer.write_all(CSV_HEADER.as_bytes()));
+            output.bytes += CSV_HEADER.len() as u64;
+        }
+        self.output = Some(output);
+        self.enforce_cap()
+    }
+
+    // Deletes the oldest files until they fit in the cap alongside a full
+    // current file.
+    fn enforce_cap(&mut self) -> io::Result<()> {
+        let current = self.output.as_ref().map(|output| output.path.clone());
+        let mut sizes = vec![];
+        for path in try!(self.files()) {
+            if Some(&path) != current.as_ref() {
+                let len = try!(fs::metadata(&path)).len();
+                sizes.push((path, len));
+            }
+        }
+        let mut total: u64 = sizes.iter().map(|&(_, len)| len).sum();
+        for (path, len) in sizes {
+            if total + self.config.max_file_bytes <= self.config.max_total_bytes {
+                break;
+            }
+            info!(path = %path.display(), "deleting old log file to stay within the disk cap");
+            try!(fs::remove_file(&path));
+            total -= len;
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}-", self.config.prefix);
+        let extension = format!(".{}", self.config.format.extension());
+        let mut files = vec![];
+        for entry in try!(fs::read_dir(&self.config.directory)) {
+            let entry = try!(entry);
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && name.ends_with(&extension) {
+                files.push(entry.path());
+            }
+        }
+        // Names are zero-padded, so they sort oldest first.
+        files.sort();
+        Ok(files)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        match self.output {
+            Some(ref mut output) => {
+                try!(output.writer.flush());
+                output.writer.get_ref().sync_data()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+}
+
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = since_epoch(time);
+    format!("{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis())
+}
+
+fn csv<M: Measurement>(port: &str, module: &str, reading: &Reading<M>) -> String {
+    let timestamp = timestamp(reading.timestamp);
+    let mut out = String::new();
+    for value in reading.value.values() {
+        let _ = writeln!(out, "{},{},{},{},{},{}", timestamp, csv_field(port), csv_field(module),
+                         csv_field(value.name), value.value, value.unit);
+    }
+    out
+}
+
+// Quotes a field containing a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// One line of a JSON-lines file. Values that aren't finite are written as
+// null, since JSON has no NaN or infinity.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: f64,
+    port: &'a str,
+    module: &'a str,
+    values: Vec<Value>,
+}
+
+fn json<M: Measurement>(port: &str, module: &str, reading: &Reading<M>) -> io::Result<String> {
+    let since_epoch = since_epoch(reading.timestamp);
+    let record = Record {
+        timestamp: since_epoch.as_secs() as f64 + (since_epoch.subsec_millis() as f64) / 1000.0,
+        port: port,
+        module: module,
+        values: reading.value.values(),
+    };
+    let mut line = try!(serde_json::to_string(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+    line.push('\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use tessel::sensor::{Acceleration, Celsius, G};
+    use self::tempfile::TempDir;
+
+    fn fixture(dir: &TempDir, format: Format) -> Config {
+        Config {
+            directory: dir.path().join("readings"),
+            format: format,
+            flush_interval_ms: 0,
+            ..Config::default()
+        }
+    }
+
+    fn read(path: &PathBuf) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn writes_csv_and_json_lines() {
+        let acceleration = Acceleration { x: G(0.5), y: G(-1.0), z: G(0.0) };
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture(&dir, Format::Csv);
+        let logger = Logger::new(config.clone()).unwrap();
+        logger.log("a", "accel-mma84", &Reading { timestamp: at(1_500), value: acceleration }).unwrap();
+        logger.log("b", "odd,name", &Reading { timestamp: at(2_000), value: Celsius(21.5) }).unwrap();
+        let files = logger.files().unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("timestamp,port,module,quantity,value,unit\n\
+                    1.500,a,accel-mma84,x,0.5,g\n\
+                    1.500,a,accel-mma84,y,-1,g\n\
+                    1.500,a,accel-mma84,z,0,g\n\
+                    2.000,b,\"odd,name\",temperature,21.5,°C\n", read(&files[0]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture(&dir, Format::JsonLines);
+        let logger = Logger::new(config.clone()).unwrap();
+        logger.log("a", "accel-mma84", &Reading { timestamp: at(1_500), value: acceleration }).unwrap();
+        let files = logger.files().unwrap();
+        assert!(files[0].to_string_lossy().ends_with(".jsonl"));
+        assert_eq!("{\"timestamp\":1.5,\"port\":\"a\",\"module\":\"accel-mma84\",\"values\":[\
+                    {\"name\":\"x\",\"value\":0.5,\"unit\":\"g\"},\
+                    {\"name\":\"y\",\"value\":-1.0,\"unit\":\"g\"},\
+                    {\"name\":\"z\",\"value\":0.0,\"unit\":\"g\"}]}\n", read(&files[0]));
+    }
+
+    #[test]
+    fn rotates_and_caps_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_file_bytes: 100,
+            max_total_bytes: 250,
+            ..fixture(&dir, Format::Csv)
+        };
+        let logger = Logger::new(config.clone()).unwrap();
+        for i in 0..20 {
+            logger.log("b", "climate-si7020", &Reading { timestamp: at(i), value: Celsius(20.0) }).unwrap();
+        }
+        logger.flush().unwrap();
+
+        let files = logger.files().unwrap();
+        assert!(files.len() >= 2);
+        let total: u64 = files.iter().map(|path| fs::metadata(path).unwrap().len()).sum();
+        assert!(total <= 250);
+        for path in &files {
+            let contents = read(path);
+            assert!(contents.starts_with(CSV_HEADER));
+            assert!(contents.len() <= 100);
+        }
+        // The newest readings survive.
+        assert!(read(files.last().unwrap()).contains("0.019,b,climate-si7020"));
+    }
+}
@@ -0,0 +1,95 @@
+extern crate accel_mma84;
+extern crate climate_si7020;
+#[macro_use] extern crate serde_derive;
+extern crate tessel;
+extern crate tessel_board;
+extern crate tessel_logger;
+extern crate toml;
+#[macro_use] extern crate tracing;
+
+use accel_mma84::Accelerometer;
+use climate_si7020::Climate;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tessel::scheduler::Scheduler;
+use tessel::sensor::Sensor;
+use tessel::{shutdown, Tessel};
+use tessel_board::{Manifest, Registry};
+use tessel_logger::{Config, Logger};
+
+const DEFAULT_MANIFEST_PATH: &'static str = "/etc/tessel/board.toml";
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+// The `[logger]` table of the board manifest.
+#[derive(Deserialize)]
+struct Settings {
+    #[serde(default)]
+    logger: Config,
+}
+
+// Logs each module in the board manifest, every `log_interval_ms` set in
+// its port's table.
+fn main() {
+    // Flush the log and return outputs to a safe state on CTRL + C.
+    shutdown::handle_signals().unwrap();
+
+    let path = env::args().nth(1).unwrap_or(DEFAULT_MANIFEST_PATH.to_string());
+    let mut source = String::new();
+    File::open(&path).and_then(|mut file| file.read_to_string(&mut source))
+        .expect("Could not read the board manifest.");
+    let manifest = Manifest::parse(&source).expect("Could not parse the board manifest.");
+    let settings: Settings = toml::from_str(&source).expect("Invalid logger settings.");
+
+    let ports = Tessel::ports().expect("Could not open the module ports.");
+    let mut board = Registry::default().build(&manifest, ports).expect("Could not set up the modules.");
+    let logger = Arc::new(Logger::new(settings.logger).expect("Could not open the log directory."));
+
+    let mut scheduler = Scheduler::new();
+    for (port, config) in &manifest.ports {
+        let interval = config.get_u64("log_interval_ms").expect("Invalid log interval.")
+            .unwrap_or(DEFAULT_INTERVAL_MS);
+        let interval = Duration::from_millis(interval);
+        match &config.module[..] {
+            "accel-mma84" => {
+                let accel: Accelerometer = board.take(port).unwrap();
+                add(&mut scheduler, &logger, port, &config.module, accel, interval);
+            }
+            "climate-si7020" => {
+                let climate: Climate = board.take(port).unwrap();
+                add(&mut scheduler, &logger, port, &config.module, climate, interval);
+            }
+            module => {
+                info!(port = %port, module, "not logging a module that takes no readings");
+                continue;
+            }
+        }
+        info!(port = %port, module = %config.module, ?interval, "logging module");
+    }
+
+    loop {
+        thread::park();
+    }
+}
+
+fn add<S>(scheduler: &mut Scheduler, logger: &Arc<Logger>, port: &str, module: &str, sensor: S, interval: Duration)
+    where S: Sensor + Send + 'static
+{
+    let logger = logger.clone();
+    let (port, module) = (port.to_string(), module.to_string());
+    let name = format!("{} on port {}", module, port);
+    scheduler.add_callback(&name, sensor, interval, move |reading| {
+        // The scheduler already logs and counts failed reads.
+        let reading = match reading {
+            Ok(reading) => reading,
+            Err(_) => return true,
+        };
+        if let Err(error) = logger.log(&port, &module, &reading) {
+            warn!(port = %port, module = %module, %error, "could not log a reading");
+        }
+        true
+    });
+}
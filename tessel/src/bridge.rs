@@ -0,0 +1,156 @@
+//! Client side of the `tessel-bridge` network protocol.
+//!
+//! The bridge daemon runs on a Tessel and forwards each module port's
+//! protocol stream over TCP. After a short handshake, which names the port
+//! and presents a shared token, the connection carries exactly the bytes
+//! that would otherwise go over `/var/run/tessel/port_*`, so any
+//! `PortSocket` can use it as its transport.
+//!
+//! Setting `TESSEL_BRIDGE` (and `TESSEL_BRIDGE_TOKEN`) in the environment
+//! makes `Tessel::ports()` connect to a bridge instead of the local sockets.
+//!
+//! The token is sent in the clear, so only expose the bridge on networks
+//! you trust.
+
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use protocol::reply;
+
+/// Environment variable holding the `host:port` of a bridge to connect to.
+pub const ADDRESS_VAR: &'static str = "TESSEL_BRIDGE";
+/// Environment variable holding the shared bridge token.
+pub const TOKEN_VAR: &'static str = "TESSEL_BRIDGE_TOKEN";
+/// TCP port the bridge daemon listens on by default.
+pub const DEFAULT_PORT: u16 = 7373;
+
+const MAGIC: &'static [u8; 4] = b"T2BR";
+const VERSION: u8 = 1;
+
+/// Identifies one of the Tessel's module ports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PortId {
+    A,
+    B,
+}
+
+impl PortId {
+    fn to_byte(self) -> u8 {
+        match self {
+            PortId::A => b'a',
+            PortId::B => b'b',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<PortId> {
+        match byte {
+            b'a' => Some(PortId::A),
+            b'b' => Some(PortId::B),
+            _ => None,
+        }
+    }
+}
+
+/// Connects to a bridge and requests the stream for `port`.
+///
+/// The returned stream is ready to be handed to `Port::with_transport`.
+pub fn connect<A: ToSocketAddrs>(addr: A, port: PortId, token: &str) -> io::Result<TcpStream> {
+    let mut stream = try!(TcpStream::connect(addr));
+    try!(stream.set_nodelay(true));
+    try!(write_request(&mut stream, port, token));
+
+    let mut answer = [0];
+    try!(stream.read_exact(&mut answer));
+    if answer[0] != reply::ACK.0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Bridge rejected the handshake."));
+    }
+    Ok(stream)
+}
+
+/// Reads the bridge address and token from the environment, if configured.
+pub fn from_env() -> Option<(String, String)> {
+    env::var(ADDRESS_VAR).ok().map(|addr| {
+        (addr, env::var(TOKEN_VAR).unwrap_or_default())
+    })
+}
+
+/// Writes the client half of the handshake.
+pub fn write_request<W: Write>(stream: &mut W, port: PortId, token: &str) -> io::Result<()> {
+    let token = token.as_bytes();
+    if token.len() > u8::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Bridge token is too long."));
+    }
+    try!(stream.write_all(MAGIC));
+    try!(stream.write_all(&[VERSION, port.to_byte(), token.len() as u8]));
+    stream.write_all(token)
+}
+
+/// Reads and verifies the client half of the handshake, then answers it.
+///
+/// This is the daemon's side of `connect`. It returns the requested port
+/// when the token matches `token`, and an error (after telling the client)
+/// otherwise. Set a read timeout on `stream` first, or a client that never
+/// sends the handshake blocks this call forever.
+pub fn accept_request<S: Read + Write>(stream: &mut S, token: &str) -> io::Result<PortId> {
+    let mut header = [0; 7];
+    try!(stream.read_exact(&mut header));
+    let mut presented = vec![0; header[6] as usize];
+    try!(stream.read_exact(&mut presented));
+
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        try!(stream.write_all(&[reply::NACK.0]));
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported bridge handshake."));
+    }
+    if !constant_time_eq(&presented, token.as_bytes()) {
+        try!(stream.write_all(&[reply::NACK.0]));
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid bridge token."));
+    }
+    match PortId::from_byte(header[5]) {
+        Some(port) => {
+            try!(stream.write_all(&[reply::ACK.0]));
+            Ok(port)
+        }
+        None => {
+            try!(stream.write_all(&[reply::NACK.0]));
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown port requested."))
+        }
+    }
+}
+
+// Compares tokens without leaking where they differ through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport;
+
+    #[test]
+    fn handshake_accepts_matching_token() {
+        let (mut client, mut server) = transport::pipe();
+        write_request(&mut client, PortId::B, "secret").unwrap();
+        assert_eq!(PortId::B, accept_request(&mut server, "secret").unwrap());
+
+        let mut answer = [0];
+        client.read_exact(&mut answer).unwrap();
+        assert_eq!(reply::ACK.0, answer[0]);
+    }
+
+    #[test]
+    fn handshake_rejects_wrong_token() {
+        let (mut client, mut server) = transport::pipe();
+        write_request(&mut client, PortId::A, "guess").unwrap();
+        let err = accept_request(&mut server, "secret").unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+
+        let mut answer = [0];
+        client.read_exact(&mut answer).unwrap();
+        assert_eq!(reply::NACK.0, answer[0]);
+    }
+}
@@ -0,0 +1,264 @@
+//! Client side of the `tessel-broker` port multiplexer.
+//!
+//! Only one process can safely write to a port socket, because commands
+//! from two processes would interleave mid-transaction. The broker daemon
+//! owns the port socket instead and lets several clients take turns: each
+//! `Transaction` a client runs is granted the port exclusively and its
+//! replies are routed back only to that client. Bytes the coprocessor sends
+//! outside any transaction are asynchronous events, and are copied to every
+//! client that has subscribed to them.
+//!
+//! Setting `TESSEL_BROKER` in the environment makes `Tessel::ports()`
+//! connect through the broker instead of directly to spid.
+//!
+//! Messages between client and broker are framed as a kind byte, a
+//! big-endian `u16` length and that many bytes of payload.
+
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+use transport::Transport;
+use unix_socket::UnixStream;
+
+/// Path of the broker socket for port A.
+pub const PORT_A_PATH: &'static str = "/var/run/tessel/broker_a";
+/// Path of the broker socket for port B.
+pub const PORT_B_PATH: &'static str = "/var/run/tessel/broker_b";
+/// Environment variable that makes `Tessel::ports()` use the broker.
+pub const ENABLE_VAR: &'static str = "TESSEL_BROKER";
+
+/// Frame kinds exchanged with the broker.
+pub mod frame {
+    /// Client: request exclusive use of the port. Broker: request granted.
+    pub const BEGIN: u8 = 0x01;
+    /// Client: command bytes for the port. Broker: reply bytes.
+    pub const DATA: u8 = 0x02;
+    /// Client: release the port.
+    pub const END: u8 = 0x03;
+    /// Client: start receiving asynchronous events. Broker: subscribed.
+    pub const SUBSCRIBE: u8 = 0x04;
+    /// Broker: asynchronous bytes received outside any transaction.
+    pub const EVENT: u8 = 0x05;
+}
+
+/// Returns true if `TESSEL_BROKER` is set in the environment.
+pub fn enabled() -> bool {
+    env::var_os(ENABLE_VAR).is_some()
+}
+
+/// Writes one frame, splitting the payload if it does not fit.
+pub fn write_frame<W: Write>(stream: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.is_empty() {
+        return stream.write_all(&[kind, 0, 0]);
+    }
+    for slice in payload.chunks(u16::max_value() as usize) {
+        let len = slice.len() as u16;
+        try!(stream.write_all(&[kind, (len >> 8) as u8, (len & 0xFF) as u8]));
+        try!(stream.write_all(slice));
+    }
+    Ok(())
+}
+
+/// Reads one frame, returning its kind and payload. A read timeout can
+/// leave the stream in the middle of a frame, so this is only for streams
+/// that block; `BrokerStream` keeps partial frames between reads.
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 3];
+    try!(stream.read_exact(&mut header));
+    let len = ((header[1] as usize) << 8) | header[2] as usize;
+    let mut payload = vec![0; len];
+    try!(stream.read_exact(&mut payload));
+    Ok((header[0], payload))
+}
+
+/// A port transport that goes through the broker.
+pub struct BrokerStream {
+    stream: UnixStream,
+    // Reply bytes received but not yet read.
+    pending: Vec<u8>,
+    offset: usize,
+    // Bytes of frames not yet complete, kept across read timeouts.
+    incoming: Vec<u8>,
+}
+
+impl BrokerStream {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<BrokerStream> {
+        Ok(BrokerStream {
+            stream: try!(UnixStream::connect(path)),
+            pending: vec![],
+            offset: 0,
+            incoming: vec![],
+        })
+    }
+
+    // Reads the next whole frame. If a read times out, the bytes received
+    // so far are kept for the next call.
+    fn next_frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            if self.incoming.len() >= 3 {
+                let len = ((self.incoming[1] as usize) << 8) | self.incoming[2] as usize;
+                if self.incoming.len() >= 3 + len {
+                    let kind = self.incoming[0];
+                    let payload = self.incoming[3..3 + len].to_vec();
+                    self.incoming.drain(..3 + len);
+                    return Ok((kind, payload));
+                }
+            }
+
+            let mut buf = [0; 256];
+            match try!(self.stream.read(&mut buf)) {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Broker closed the connection.")),
+                len => self.incoming.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+
+    // Reads frames until one of the given kind arrives, keeping any reply
+    // data seen on the way.
+    fn wait_for(&mut self, kind: u8) -> io::Result<Vec<u8>> {
+        loop {
+            let (received, payload) = try!(self.next_frame());
+            if received == kind {
+                return Ok(payload);
+            }
+            if received == frame::DATA {
+                self.pending.drain(..self.offset);
+                self.offset = 0;
+                self.pending.extend(payload);
+            }
+        }
+    }
+}
+
+impl Read for BrokerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offset == self.pending.len() {
+            self.pending = try!(self.wait_for(frame::DATA));
+            self.offset = 0;
+        }
+
+        let available = &self.pending[self.offset..];
+        let len = buf.len().min(available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl Write for BrokerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(write_frame(&mut self.stream, frame::DATA, buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for BrokerStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn begin_transaction(&mut self) -> io::Result<()> {
+        try!(write_frame(&mut self.stream, frame::BEGIN, &[]));
+        // Block until the broker hands us the port, however long other
+        // clients keep it; reply timeouts only apply once we have it.
+        let timeout = try!(self.stream.read_timeout());
+        try!(self.stream.set_read_timeout(None));
+        let granted = self.wait_for(frame::BEGIN);
+        try!(self.stream.set_read_timeout(timeout));
+        try!(granted);
+        Ok(())
+    }
+
+    fn end_transaction(&mut self) -> io::Result<()> {
+        write_frame(&mut self.stream, frame::END, &[])
+    }
+}
+
+/// Subscribes to the asynchronous events of a brokered port.
+///
+/// # Example
+/// ```rust,no_run
+/// use tessel::broker;
+///
+/// for event in broker::subscribe(broker::PORT_A_PATH).unwrap() {
+///     println!("Port A event: {:?}", event.unwrap());
+/// }
+/// ```
+pub fn subscribe<P: AsRef<Path>>(path: P) -> io::Result<Events> {
+    let mut stream = try!(UnixStream::connect(path));
+    try!(write_frame(&mut stream, frame::SUBSCRIBE, &[]));
+    // Events sent after the broker's acknowledgement are never missed.
+    loop {
+        match try!(read_frame(&mut stream)) {
+            (frame::SUBSCRIBE, _) => break,
+            _ => continue,
+        }
+    }
+    Ok(Events {
+        stream: stream,
+    })
+}
+
+/// Iterator over the raw asynchronous bytes of a brokered port.
+pub struct Events {
+    stream: UnixStream,
+}
+
+impl Iterator for Events {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        loop {
+            match read_frame(&mut self.stream) {
+                Ok((frame::EVENT, payload)) => return Some(Ok(payload)),
+                Ok(_) => continue,
+                // The broker went away.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport;
+
+    #[test]
+    fn frames_round_trip() {
+        let (mut a, mut b) = transport::pipe();
+        write_frame(&mut a, frame::DATA, &[0x13, 0x3A]).unwrap();
+        write_frame(&mut a, frame::END, &[]).unwrap();
+        assert_eq!((frame::DATA, vec![0x13, 0x3A]), read_frame(&mut b).unwrap());
+        assert_eq!((frame::END, vec![]), read_frame(&mut b).unwrap());
+    }
+
+    #[test]
+    fn timeout_mid_frame_keeps_partial_frame() {
+        let (client, mut broker) = UnixStream::pair().unwrap();
+        let mut stream = BrokerStream {
+            stream: client,
+            pending: vec![],
+            offset: 0,
+            incoming: vec![],
+        };
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        broker.write_all(&[frame::DATA, 0]).unwrap();
+        let mut reply = [0; 2];
+        assert!(stream.read_exact(&mut reply).is_err());
+        broker.write_all(&[2, 0xAA, 0xBB]).unwrap();
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!([0xAA, 0xBB], reply);
+    }
+}
@@ -0,0 +1,233 @@
+//! The Tessel 2's onboard button.
+//!
+//! The button is read through its sysfs GPIO `value` file. All paths are
+//! relative to a configurable root so the button can be exercised against
+//! fake files.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// GPIO value file for the user button, relative to the filesystem root.
+const BUTTON_VALUE_PATH: &'static str = "sys/class/gpio/gpio38/value";
+// How often the button is sampled while waiting for it.
+const POLL_INTERVAL_MS: u64 = 10;
+const DEFAULT_DEBOUNCE_MS: u64 = 30;
+const DEFAULT_LONG_PRESS_MS: u64 = 1500;
+
+/// A change in the button's debounced state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ButtonEvent {
+    Pressed,
+    Released,
+    /// The button has been held down for the long-press duration. Sent once
+    /// per press, before the matching `Released`.
+    LongPress,
+}
+
+/// The onboard button.
+/// # Example
+/// ```rust,no_run
+/// use tessel::Tessel;
+/// use tessel::button::ButtonEvent;
+///
+/// let t = Tessel::new();
+/// for event in t.button().events() {
+///     if event == ButtonEvent::LongPress {
+///         println!("Factory reset requested.");
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Button {
+    path: PathBuf,
+    // The GPIO reads low while the button is held.
+    active_low: bool,
+    debounce: Duration,
+    long_press: Duration,
+}
+
+impl Button {
+    pub fn new() -> Button {
+        Button::with_root("/")
+    }
+
+    /// Creates a button whose sysfs files live under `root` instead of `/`.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Button {
+        Button::with_path(root.as_ref().join(BUTTON_VALUE_PATH), true)
+    }
+
+    /// Creates a button read from an arbitrary GPIO value file.
+    pub fn with_path<P: AsRef<Path>>(path: P, active_low: bool) -> Button {
+        Button {
+            path: path.as_ref().to_path_buf(),
+            active_low: active_low,
+            debounce: Duration::from_millis(DEFAULT_DEBOUNCE_MS),
+            long_press: Duration::from_millis(DEFAULT_LONG_PRESS_MS),
+        }
+    }
+
+    /// Sets how long the button must stay in a new state before it counts.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Sets how long the button must be held to send `LongPress`.
+    pub fn set_long_press(&mut self, long_press: Duration) {
+        self.long_press = long_press;
+    }
+
+    /// Reads whether the button is held down right now, without debouncing.
+    pub fn is_pressed(&self) -> io::Result<bool> {
+        let mut value = String::new();
+        try!(try!(File::open(&self.path)).read_to_string(&mut value));
+        match value.trim() {
+            "0" => Ok(self.active_low),
+            "1" => Ok(!self.active_low),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected button GPIO value.")),
+        }
+    }
+
+    /// Blocks until the button is pressed.
+    pub fn wait_for_press(&self) -> io::Result<()> {
+        self.wait_for(ButtonEvent::Pressed)
+    }
+
+    /// Blocks until the button is released.
+    pub fn wait_for_release(&self) -> io::Result<()> {
+        self.wait_for(ButtonEvent::Released)
+    }
+
+    /// Returns a channel of debounced button events, watched by a
+    /// background thread until the receiver is dropped or the button can
+    /// no longer be read.
+    pub fn events(&self) -> Receiver<ButtonEvent> {
+        let (tx, rx) = mpsc::channel();
+        let button = self.clone();
+        thread::spawn(move || {
+            let mut debouncer = match button.is_pressed() {
+                Ok(pressed) => Debouncer::new(pressed, Instant::now()),
+                Err(_) => return,
+            };
+            loop {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                let pressed = match button.is_pressed() {
+                    Ok(pressed) => pressed,
+                    Err(_) => return,
+                };
+                for event in debouncer.update(pressed, Instant::now(), button.debounce, button.long_press) {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    fn wait_for(&self, wanted: ButtonEvent) -> io::Result<()> {
+        let mut debouncer = Debouncer::new(try!(self.is_pressed()), Instant::now());
+        loop {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            let pressed = try!(self.is_pressed());
+            if debouncer.update(pressed, Instant::now(), self.debounce, self.long_press).contains(&wanted) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Turns raw samples into debounced events.
+struct Debouncer {
+    // Debounced state.
+    pressed: bool,
+    // When the raw state last differed from the debounced state, if it does.
+    changing_since: Option<Instant>,
+    pressed_at: Instant,
+    long_press_sent: bool,
+}
+
+impl Debouncer {
+    fn new(pressed: bool, now: Instant) -> Debouncer {
+        Debouncer {
+            pressed: pressed,
+            changing_since: None,
+            pressed_at: now,
+            // Don't report a long press for a button already held at start.
+            long_press_sent: pressed,
+        }
+    }
+
+    fn update(&mut self, raw: bool, now: Instant, debounce: Duration, long_press: Duration) -> Vec<ButtonEvent> {
+        let mut events = vec![];
+        if raw == self.pressed {
+            self.changing_since = None;
+        } else {
+            let since = *self.changing_since.get_or_insert(now);
+            if now.duration_since(since) >= debounce {
+                self.pressed = raw;
+                self.changing_since = None;
+                if raw {
+                    self.pressed_at = since;
+                    self.long_press_sent = false;
+                    events.push(ButtonEvent::Pressed);
+                } else {
+                    events.push(ButtonEvent::Released);
+                }
+            }
+        }
+
+        if self.pressed && !self.long_press_sent && now.duration_since(self.pressed_at) >= long_press {
+            self.long_press_sent = true;
+            // A long press is reported before the release that ends it.
+            events.insert(0, ButtonEvent::LongPress);
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::fs::{self, File};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reads_sysfs_value() {
+        let fake = tempfile::tempdir().unwrap();
+        let root = fake.path();
+        let path = root.join(BUTTON_VALUE_PATH);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let button = Button::with_root(&root);
+
+        File::create(&path).unwrap().write_all(b"1\n").unwrap();
+        assert!(!button.is_pressed().unwrap());
+        File::create(&path).unwrap().write_all(b"0\n").unwrap();
+        assert!(button.is_pressed().unwrap());
+    }
+
+    #[test]
+    fn debounces_and_detects_long_press() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let debounce = Duration::from_millis(30);
+        let long_press = Duration::from_millis(1000);
+        let mut debouncer = Debouncer::new(false, start);
+
+        // A 10ms glitch is ignored.
+        assert!(debouncer.update(true, ms(10), debounce, long_press).is_empty());
+        assert!(debouncer.update(false, ms(20), debounce, long_press).is_empty());
+
+        assert!(debouncer.update(true, ms(100), debounce, long_press).is_empty());
+        assert_eq!(vec![ButtonEvent::Pressed], debouncer.update(true, ms(130), debounce, long_press));
+        assert_eq!(vec![ButtonEvent::LongPress], debouncer.update(true, ms(1100), debounce, long_press));
+        assert!(debouncer.update(true, ms(1200), debounce, long_press).is_empty());
+        assert!(debouncer.update(false, ms(1300), debounce, long_press).is_empty());
+        assert_eq!(vec![ButtonEvent::Released], debouncer.update(false, ms(1330), debounce, long_press));
+    }
+}
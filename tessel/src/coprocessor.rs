@@ -0,0 +1,275 @@
+//! The SAMD21 coprocessor that drives the module ports.
+//!
+//! The stock t2-firmware speaks protocol revision 0, which has no command
+//! reporting the firmware's version, so by default `identity` only checks
+//! that the coprocessor answers an `Echo`. Protocol revision 1 adds the
+//! `Version` command, answered with the firmware version and serial number.
+//! Firmware without it may take the unknown opcode and the bytes after it
+//! for other commands, so the query is only sent once `set_version_query`
+//! says the firmware is known to define it. An `Echo` still follows the
+//! query, and if the echo comes back first the firmware is reported as
+//! revision 0.
+//!
+//! The stock image doesn't export the coprocessor's reset line, so `reset`
+//! pulses the GPIO value file named by `TESSEL_COPROCESSOR_RESET` or set
+//! with `set_reset_path`, and fails with `NotFound` without one.
+//! # Example
+//! ```rust,no_run
+//! use tessel::Tessel;
+//!
+//! let (port_a, _) = Tessel::ports().unwrap();
+//! let coprocessor = Tessel::new().coprocessor(&port_a);
+//! // Refuse to start on firmware this library can't drive.
+//! let identity = coprocessor.check_compatible().unwrap();
+//! println!("Coprocessor firmware: {:?}", identity.firmware);
+//! ```
+
+use protocol::{reply, Command, PortSocket, Transaction};
+use std::env;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The newest protocol revision this library speaks.
+pub const PROTOCOL_REVISION: u8 = 1;
+/// The oldest protocol revision this library speaks.
+pub const MIN_PROTOCOL_REVISION: u8 = 0;
+
+/// Environment variable naming the GPIO value file for the coprocessor's
+/// active-low reset line.
+pub const RESET_VAR: &'static str = "TESSEL_COPROCESSOR_RESET";
+const RESET_PULSE_MS: u64 = 10;
+// Time for the coprocessor's bootloader to hand over to the firmware.
+const BOOT_DELAY_MS: u64 = 100;
+
+// Echoed after each version query. No firmware reports revision 0xFF, so
+// a reply starting with it can only be the echo.
+const ECHO_MARKER: [u8; 4] = [0xFF, 0xFE, 0xFD, 0xFC];
+const SERIAL_LEN: usize = 16;
+// How long to wait for the identity when the port has no timeout of its own.
+const IDENTITY_TIMEOUT_MS: u64 = 500;
+
+/// A firmware version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// What the coprocessor reports about itself.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// The protocol revision, 0 for firmware without the `Version` command
+    /// or when it wasn't queried.
+    pub protocol: u8,
+    /// The firmware version, if the firmware reports it.
+    pub firmware: Option<FirmwareVersion>,
+    /// The SAMD21's 128-bit serial number, if the firmware reports it.
+    pub serial: Option<[u8; SERIAL_LEN]>,
+}
+
+impl Identity {
+    /// Returns true if this library can drive the firmware.
+    pub fn is_compatible(&self) -> bool {
+        self.protocol <= PROTOCOL_REVISION
+    }
+}
+
+/// A handle on the coprocessor, talking through one of the ports.
+pub struct Coprocessor {
+    socket: Arc<Mutex<PortSocket>>,
+    reset_path: Option<PathBuf>,
+    version_query: bool,
+}
+
+impl Coprocessor {
+    /// Creates a handle that queries through `socket` and resets the
+    /// coprocessor through the GPIO named by `TESSEL_COPROCESSOR_RESET`,
+    /// under `root`.
+    pub fn new<P: AsRef<Path>>(socket: Arc<Mutex<PortSocket>>, root: P) -> Coprocessor {
+        let root = root.as_ref();
+        Coprocessor {
+            socket: socket,
+            reset_path: env::var(RESET_VAR).ok().map(|path| root.join(path.trim_start_matches('/'))),
+            version_query: false,
+        }
+    }
+
+    /// Sends the `Version` query in `identity`. Only enable it for firmware
+    /// known to speak protocol revision 1.
+    pub fn set_version_query(&mut self, enabled: bool) {
+        self.version_query = enabled;
+    }
+
+    /// Sets the GPIO value file for the coprocessor's reset line.
+    pub fn set_reset_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.reset_path = Some(path.as_ref().to_path_buf());
+    }
+
+    /// Asks the coprocessor for its protocol revision, and its firmware
+    /// version and serial number if the version query is enabled. Fails
+    /// with `TimedOut` if the coprocessor doesn't answer within the port's
+    /// timeout, or half a second if the port has none.
+    pub fn identity(&self) -> io::Result<Identity> {
+        let timeout = self.socket.lock().unwrap().timeout()
+            .unwrap_or(Duration::from_millis(IDENTITY_TIMEOUT_MS));
+        let mut sock = try!(Transaction::begin_with_timeout(&self.socket, Some(timeout)));
+        if self.version_query {
+            try!(sock.write_command(Command::Version));
+        }
+        try!(sock.write_command(Command::Echo(&ECHO_MARKER)));
+
+        let mut header = [0; 5];
+        try!(sock.read_exact(&mut header));
+        if header[0] != reply::DATA.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply to version query."));
+        }
+        if header[1..] == ECHO_MARKER {
+            return Ok(Identity {
+                protocol: 0,
+                firmware: None,
+                serial: None,
+            });
+        }
+
+        // DATA, revision, major, minor, patch, then the serial number.
+        let mut serial = [0; SERIAL_LEN];
+        try!(sock.read_exact(&mut serial));
+        let mut echo = [0; 5];
+        try!(sock.read_exact(&mut echo));
+        if echo[0] != reply::DATA.0 || echo[1..] != ECHO_MARKER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Echo reply did not match."));
+        }
+        Ok(Identity {
+            protocol: header[1],
+            firmware: Some(FirmwareVersion {
+                major: header[2],
+                minor: header[3],
+                patch: header[4],
+            }),
+            serial: Some(serial),
+        })
+    }
+
+    /// Returns the coprocessor's identity, or an error if this library
+    /// can't drive its firmware.
+    pub fn check_compatible(&self) -> io::Result<Identity> {
+        let identity = try!(self.identity());
+        if !identity.is_compatible() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!(
+                "Coprocessor speaks protocol revision {}, but this library supports {} to {}.",
+                identity.protocol, MIN_PROTOCOL_REVISION, PROTOCOL_REVISION)));
+        }
+        Ok(identity)
+    }
+
+    /// Resets the coprocessor by pulsing its reset line, then restores the
+    /// configuration of the port this handle talks through. Other ports lose
+    /// their configuration; call `Port::restore` on them.
+    pub fn reset(&self) -> io::Result<()> {
+        let path = try!(self.reset_path.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+            "No coprocessor reset line configured; set TESSEL_COPROCESSOR_RESET to its GPIO value file.")));
+        // Hold the port so nothing is sent while the coprocessor reboots.
+        let mut sock = try!(Transaction::begin(&self.socket));
+        try!(write_gpio(path, false));
+        thread::sleep(Duration::from_millis(RESET_PULSE_MS));
+        try!(write_gpio(path, true));
+        thread::sleep(Duration::from_millis(BOOT_DELAY_MS));
+        sock.restore()
+    }
+}
+
+fn write_gpio(path: &Path, high: bool) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).truncate(true).open(path));
+    file.write_all(if high { b"1" } else { b"0" })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use protocol::{reply, PortSocket};
+    use std::fs::File;
+    use std::sync::{Arc, Mutex};
+    use transport;
+
+    #[test]
+    fn identifies_current_and_legacy_firmware() {
+        let (local, mut remote) = transport::pipe();
+        let socket = Arc::new(Mutex::new(PortSocket::with_transport(Box::new(local))));
+        let mut coprocessor = Coprocessor::new(socket, "/nonexistent");
+
+        // The stock firmware is only sent an echo.
+        remote.write_all(&[reply::DATA.0]).unwrap();
+        remote.write_all(&ECHO_MARKER).unwrap();
+        assert_eq!(Identity { protocol: 0, firmware: None, serial: None },
+                   coprocessor.check_compatible().unwrap());
+        let mut sent = [0; 6];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x02, 4, 0xFF, 0xFE, 0xFD, 0xFC], sent);
+
+        coprocessor.set_version_query(true);
+        let mut replies = vec![reply::DATA.0, 1, 0, 4, 2];
+        replies.extend(&[0xA5; SERIAL_LEN]);
+        replies.push(reply::DATA.0);
+        replies.extend(&ECHO_MARKER);
+        remote.write_all(&replies).unwrap();
+        let identity = coprocessor.check_compatible().unwrap();
+        assert_eq!(Some(FirmwareVersion { major: 0, minor: 4, patch: 2 }), identity.firmware);
+        assert_eq!(Some([0xA5; SERIAL_LEN]), identity.serial);
+
+        let mut sent = [0; 7];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x1D, 0x02, 4, 0xFF, 0xFE, 0xFD, 0xFC], sent);
+
+        // Legacy firmware only answers the echo.
+        remote.write_all(&[reply::DATA.0]).unwrap();
+        remote.write_all(&ECHO_MARKER).unwrap();
+        assert_eq!(0, coprocessor.check_compatible().unwrap().protocol);
+
+        // A newer protocol than this library knows is refused.
+        let mut replies = vec![reply::DATA.0, PROTOCOL_REVISION + 1, 1, 0, 0];
+        replies.extend(&[0; SERIAL_LEN]);
+        replies.push(reply::DATA.0);
+        replies.extend(&ECHO_MARKER);
+        remote.write_all(&replies).unwrap();
+        assert!(coprocessor.check_compatible().is_err());
+    }
+
+    #[test]
+    fn silent_coprocessor_times_out() {
+        let (local, _remote) = transport::pipe();
+        let socket = Arc::new(Mutex::new(PortSocket::with_transport(Box::new(local))));
+        socket.lock().unwrap().set_timeout(Some(Duration::from_millis(10)));
+        let coprocessor = Coprocessor::new(socket, "/nonexistent");
+        assert_eq!(io::ErrorKind::TimedOut, coprocessor.identity().unwrap_err().kind());
+    }
+
+    #[test]
+    fn reset_pulses_line_and_restores_configuration() {
+        let fake = tempfile::tempdir().unwrap();
+        let path = fake.path().join("coprocessor_reset");
+        File::create(&path).unwrap().write_all(b"1").unwrap();
+
+        let (local, mut remote) = transport::pipe();
+        let socket = Arc::new(Mutex::new(PortSocket::with_transport(Box::new(local))));
+        socket.lock().unwrap().write_command(Command::EnableI2c { baud: 0x3A }).unwrap();
+        let mut coprocessor = Coprocessor::new(socket, "/nonexistent");
+        coprocessor.reset_path = None;
+        assert_eq!(io::ErrorKind::NotFound, coprocessor.reset().unwrap_err().kind());
+        coprocessor.set_reset_path(&path);
+        coprocessor.reset().unwrap();
+
+        let mut value = String::new();
+        File::open(&path).unwrap().read_to_string(&mut value).unwrap();
+        assert_eq!("1", value);
+        let mut sent = [0; 4];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x0C, 0x3A, 0x0C, 0x3A], sent);
+    }
+}
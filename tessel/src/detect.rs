@@ -0,0 +1,203 @@
+//! Detection of the modules plugged into the ports.
+//!
+//! Each port is probed over I2C for the identification registers of the
+//! modules this library has drivers for. Probing enables I2C on the port
+//! and disables it again afterwards, so a port can still be handed to any
+//! driver once it has been probed.
+//! # Example
+//! ```rust,no_run
+//! use tessel::{detect, Tessel};
+//!
+//! let ports = Tessel::ports().unwrap();
+//! let report = detect::detect(&ports);
+//! println!("Port A: {:?}, port B: {:?}", report.port_a, report.port_b);
+//! ```
+
+use protocol::{Command, Transaction};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use {I2cPort, Operation, OperationResult, Port};
+
+/// I2C address of the MMA8452Q accelerometer on the accel-mma84 module.
+pub const ACCEL_ADDRESS: u8 = 0x1D;
+const ACCEL_WHO_AM_I: u8 = 0x0D;
+const ACCEL_ID: u8 = 0x2A;
+
+/// I2C address of the Si7020 on the climate-si7020 module.
+pub const CLIMATE_ADDRESS: u8 = 0x40;
+const CLIMATE_READ_ID: [u8; 2] = [0xFC, 0xC9];
+const CLIMATE_ID: u8 = 0x14;
+
+/// I2C addresses the PCA9685 on the servo-pca9685 module can answer on,
+/// 0x73 by default. Address pins 2 and 3 pull the low address bits down.
+pub const SERVO_ADDRESSES: [u8; 4] = [0x73, 0x72, 0x71, 0x70];
+// Every PCA9685 also answers its LED All Call address, 0x70 by default, so
+// a reply there alone proves nothing. The ALLCALLADR register holding that
+// address identifies the chip instead.
+const SERVO_ALLCALLADR: u8 = 0x05;
+const SERVO_ALLCALLADR_DEFAULT: u8 = 0x70 << 1;
+
+// How long to wait for the coprocessor to answer a probe.
+const PROBE_TIMEOUT_MS: u64 = 200;
+
+/// A module recognised on a port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Module {
+    /// The MMA8452Q accelerometer module.
+    Accel,
+    /// The Si7020 temperature and humidity module.
+    Climate,
+    /// The PCA9685 servo module, at this I2C address.
+    Servo(u8),
+}
+
+impl Module {
+    /// The name of the crate that drives this module.
+    pub fn driver(&self) -> &'static str {
+        match *self {
+            Module::Accel => "accel-mma84",
+            Module::Climate => "climate-si7020",
+            Module::Servo(_) => "servo-pca9685",
+        }
+    }
+
+    /// The module's I2C address.
+    pub fn address(&self) -> u8 {
+        match *self {
+            Module::Accel => ACCEL_ADDRESS,
+            Module::Climate => CLIMATE_ADDRESS,
+            Module::Servo(address) => address,
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at 0x{:02X}", self.driver(), self.address())
+    }
+}
+
+/// The module found on each port, if any.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Report {
+    pub port_a: Option<Module>,
+    pub port_b: Option<Module>,
+}
+
+/// Probes both ports. A port whose probe fails, for example because the
+/// coprocessor did not answer in time, is logged and reported as empty.
+pub fn detect(ports: &(Port, Port)) -> Report {
+    let probe_or_none = |name: &str, port: &Port| probe(port).unwrap_or_else(|e| {
+        warn!(port = name, error = %e, "could not probe port");
+        None
+    });
+    Report {
+        port_a: probe_or_none("A", &ports.0),
+        port_b: probe_or_none("B", &ports.1),
+    }
+}
+
+/// Probes a port for a known module. Returns `None` if nothing answered.
+///
+/// A probe that times out leaves the port's socket to be resynchronised
+/// before its next transaction, so a late reply can't be mistaken for the
+/// answer to a later command.
+pub fn probe(port: &Port) -> io::Result<Option<Module>> {
+    let _span = debug_span!("detect").entered();
+    let mut i2c = I2cPort::new(port.socket.clone());
+    i2c.set_timeout(Some(Duration::from_millis(PROBE_TIMEOUT_MS)));
+    let found = probe_i2c(&mut i2c);
+
+    // Leave the pins as they were for whichever driver takes the port.
+    let mut sock = try!(Transaction::begin(&port.socket));
+    try!(sock.write_command(Command::DisableI2c));
+
+    let found = try!(found);
+    match found {
+        Some(module) => info!(%module, "detected module"),
+        None => debug!("no module detected"),
+    }
+    Ok(found)
+}
+
+fn probe_i2c(i2c: &mut I2cPort) -> io::Result<Option<Module>> {
+    let mut id = [0; 1];
+    if try!(read(i2c, ACCEL_ADDRESS, &[ACCEL_WHO_AM_I], &mut id)) && id[0] == ACCEL_ID {
+        return Ok(Some(Module::Accel));
+    }
+
+    let mut id = [0; 6];
+    if try!(read(i2c, CLIMATE_ADDRESS, &CLIMATE_READ_ID, &mut id)) && id[0] == CLIMATE_ID {
+        return Ok(Some(Module::Climate));
+    }
+
+    // The PCA9685 has no ID register; a device whose ALLCALLADR holds the
+    // power-on value is taken to be one.
+    for &address in &SERVO_ADDRESSES {
+        let mut all_call = [0; 1];
+        if try!(read(i2c, address, &[SERVO_ALLCALLADR], &mut all_call)) &&
+           all_call[0] == SERVO_ALLCALLADR_DEFAULT {
+            return Ok(Some(Module::Servo(address)));
+        }
+    }
+    Ok(None)
+}
+
+// Writes `register` and reads back into `buf`, returning false if the
+// device did not acknowledge.
+fn read(i2c: &mut I2cPort, address: u8, register: &[u8], buf: &mut [u8]) -> io::Result<bool> {
+    let results = try!(i2c.transaction(address, &mut [Operation::Write(register),
+                                                      Operation::Read(buf)]));
+    Ok(!results.contains(&OperationResult::Nack))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::reply;
+    use std::io::prelude::*;
+    use transport;
+
+    #[test]
+    fn identifies_modules_by_their_id_registers() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+
+        remote.write_all(&[reply::DATA.0, ACCEL_ID]).unwrap();
+        assert_eq!(Some(Module::Accel), probe(&port).unwrap());
+
+        remote.write_all(&[reply::NACK.0, reply::DATA.0, CLIMATE_ID, 0xFF, 0, 0, 0, 0]).unwrap();
+        assert_eq!(Some(Module::Climate), probe(&port).unwrap());
+
+        remote.write_all(&[reply::NACK.0, reply::NACK.0, reply::NACK.0,
+                           reply::DATA.0, SERVO_ALLCALLADR_DEFAULT]).unwrap();
+        assert_eq!(Some(Module::Servo(0x72)), probe(&port).unwrap());
+
+        // Something else answering at the All Call address is not a servo.
+        remote.write_all(&[reply::NACK.0; 5]).unwrap();
+        remote.write_all(&[reply::DATA.0, 0x00]).unwrap();
+        assert_eq!(None, probe(&port).unwrap());
+
+        // A device at the accelerometer's address with the wrong ID is not
+        // mistaken for one.
+        remote.write_all(&[reply::DATA.0, 0x1A, reply::NACK.0]).unwrap();
+        remote.write_all(&[reply::NACK.0; 4]).unwrap();
+        assert_eq!(None, probe(&port).unwrap());
+
+        // Each probe leaves the port with I2C disabled.
+        assert_eq!(Some(&5), port.stats().commands.get("DisableI2c"));
+    }
+
+    #[test]
+    fn port_that_times_out_is_reported_empty() {
+        let (silent, _unanswered) = transport::pipe();
+        let (local, mut remote) = transport::pipe();
+        let ports = (Port::with_transport(silent), Port::with_transport(local));
+
+        remote.write_all(&[reply::DATA.0, ACCEL_ID]).unwrap();
+        assert_eq!(Report { port_a: None, port_b: Some(Module::Accel) }, detect(&ports));
+    }
+}
@@ -1,21 +1,45 @@
 //! Tessel API and crate.
 
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate tracing;
 extern crate atomic_option;
 extern crate unix_socket;
 extern crate bit_set;
+extern crate ctrlc;
+#[cfg(feature = "serde")]
+#[macro_use] extern crate serde;
 
+pub mod bridge;
+pub mod broker;
+pub mod button;
+pub mod coprocessor;
+pub mod detect;
+pub mod network;
+pub mod power;
 pub mod protocol;
+pub mod scheduler;
+pub mod sensor;
+pub mod sequencer;
+pub mod shutdown;
+pub mod stats;
+pub mod system;
+pub mod transport;
 
 use atomic_option::AtomicOption;
-use protocol::{Command, reply, PortSocket};
-use std::fs::File;
+use power::PowerSwitch;
+use protocol::{Command, reply, PortSocket, Reconnected, Transaction, MAX_TRANSFER};
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use bit_set::BitSet;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use shutdown::SafeState;
+use transport::Transport;
 
 // TODO Corking reduces latency, as spid adds overhead for each packet
 
@@ -25,10 +49,16 @@ const PORT_A_UDS_PATH: &'static str = "/var/run/tessel/port_a";
 const PORT_B_UDS_PATH: &'static str = "/var/run/tessel/port_b";
 
 const MCU_MAX_SPEED: u32 = 48e6 as u32;
-// TODO: Replace with better name
-const MCU_MAX_SCL_RISE_TIME_NS: f64 = 1.5e-8 as f64;
-const MCU_MAGIC_DIV_FACTOR_FOR_I2C_BAUD: u8 = 2;
-const MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD: u8 = 5;
+// SCL rise time assumed when a bus has not been given its own.
+const DEFAULT_SCL_RISE_TIME_NS: u32 = 15;
+// Fastest I2C mode the SAMD21 supports in master mode (Fast-mode Plus).
+const I2C_MAX_FREQUENCY: u32 = 1_000_000;
+// Port pins used by the I2C peripheral.
+const I2C_SCL_PIN: u8 = 0;
+const I2C_SDA_PIN: u8 = 1;
+// Range of SPI clock frequencies the SAMD21 can generate.
+const SPI_MIN_FREQUENCY: u32 = 368;
+const SPI_MAX_FREQUENCY: u32 = 24_000_000;
 
 /// Primary exported Tessel object with access to module ports, LEDs, and a button.
 /// # Example
@@ -45,43 +75,120 @@ const MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD: u8 = 5;
 pub struct Tessel {
     // An array of LED structs.
     pub led: Vec<LED>,
+    // Where sysfs and the other system directories are found.
+    root: PathBuf,
 }
 
 lazy_static! {
-    // Create a tuple with two ports, one on each domain socket path.
-    static ref TESSEL_PORTS: AtomicOption<(Port, Port)> = AtomicOption::new(Box::new((
-        Port::new(PORT_A_UDS_PATH),
-        Port::new(PORT_B_UDS_PATH),
-    )));
+    // Create a tuple with two ports, one on each domain socket path, or
+    // on a remote bridge or local broker if one is configured in the environment.
+    static ref TESSEL_PORTS: AtomicOption<(Port, Port)> = AtomicOption::new(Box::new(
+        match bridge::from_env() {
+            Some((addr, token)) => {
+                let (addr_b, token_b) = (addr.clone(), token.clone());
+                (
+                    Port::with_connector(move || bridge::connect(&*addr, bridge::PortId::A, &token)).unwrap(),
+                    Port::with_connector(move || bridge::connect(&*addr_b, bridge::PortId::B, &token_b)).unwrap(),
+                )
+            }
+            None if broker::enabled() => with_power_switches((
+                Port::with_connector(|| broker::BrokerStream::connect(broker::PORT_A_PATH)).unwrap(),
+                Port::with_connector(|| broker::BrokerStream::connect(broker::PORT_B_PATH)).unwrap(),
+            )),
+            None => with_power_switches((
+                Port::new(PORT_A_UDS_PATH),
+                Port::new(PORT_B_UDS_PATH),
+            )),
+        }
+    ));
+}
+
+// Ports on this Tessel can be switched off through sysfs, if the power
+// GPIOs are configured. Remote ports can't.
+fn with_power_switches((mut a, mut b): (Port, Port)) -> (Port, Port) {
+    if let Ok(switch) = PowerSwitch::port_a("/") {
+        a.set_power_switch(switch);
+    }
+    if let Ok(switch) = PowerSwitch::port_b("/") {
+        b.set_power_switch(switch);
+    }
+    (a, b)
 }
 
 impl Tessel {
     // new() returns a Tessel struct conforming to the Tessel 2's functionality.
     pub fn new() -> Tessel {
+        Tessel::with_root("/")
+    }
+
+    /// Creates a Tessel whose LEDs, button and power switches are found
+    /// under `root` instead of `/`, such as a directory of fake sysfs files.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Tessel {
+        let root = root.as_ref().to_path_buf();
+        let leds = root.join(LED_SYSFS_PATH.trim_start_matches('/'));
+        let led = |color: &str, kind: &str| LED::with_dir(leds.join(format!("tessel:{}:{}", color, kind))).unwrap();
+
         // Create models for the four LEDs.
-        let red_led = LED::new("red", "error");
-        let amber_led = LED::new("amber", "wlan");
-        let green_led = LED::new("green", "user1");
-        let blue_led = LED::new("blue", "user2");
+        let red_led = led("red", "error");
+        let amber_led = led("amber", "wlan");
+        let green_led = led("green", "user1");
+        let blue_led = led("blue", "user2");
 
         // Return the Tessel with these fields.
         Tessel {
             led: vec![red_led, amber_led, green_led, blue_led],
+            root: root,
         }
     }
 
+    /// Takes ownership of the two module ports. Returns `None` if they
+    /// have already been taken.
+    ///
+    /// When `TESSEL_BRIDGE` is set to the address of a `tessel-bridge`
+    /// daemon, the ports are driven over the network instead of the local
+    /// spid sockets. See the `bridge` module. Likewise, setting
+    /// `TESSEL_BROKER` shares the ports with other processes through
+    /// `tessel-broker`. See the `broker` module.
     pub fn ports() -> Option<(Port, Port)> {
         TESSEL_PORTS.take(Ordering::Relaxed).map(|x| *x)
     }
+
+    /// Returns the onboard button.
+    pub fn button(&self) -> button::Button {
+        button::Button::with_root(&self.root)
+    }
+
+    /// Returns a handle on the coprocessor, which queries it through `port`.
+    pub fn coprocessor(&self, port: &Port) -> coprocessor::Coprocessor {
+        coprocessor::Coprocessor::new(port.socket.clone(), &self.root)
+    }
+
+    /// Turns power to the USB port on or off. Fails with `NotFound` unless
+    /// `TESSEL_USB_POWER` names the power GPIO.
+    pub fn usb_power(&self, on: bool) -> io::Result<()> {
+        try!(PowerSwitch::usb(&self.root)).set(on)
+    }
+
+    /// Returns whether the USB port is powered.
+    pub fn usb_power_state(&self) -> io::Result<bool> {
+        try!(PowerSwitch::usb(&self.root)).is_on()
+    }
 }
 
 /// A Port is a model of the Tessel hardware ports.
 /// # Example
 /// ```
 /// use tessel::Port;
+/// use tessel::transport;
+///
+/// // Drive a port over an in-memory pipe instead of the spid socket.
+/// let (local, _remote) = transport::pipe();
+/// let _port = Port::with_transport(local);
 /// ```
 pub struct Port {
     socket: Arc<Mutex<PortSocket>>,
+    // Switches the port's power, if it is on this Tessel.
+    power: Option<PowerSwitch>,
 }
 
 impl Port {
@@ -89,9 +196,126 @@ impl Port {
         // Create and return the port struct
         Port {
             socket: Arc::new(Mutex::new(PortSocket::new(path))),
+            power: None,
         }
     }
 
+    /// Creates a port that talks to its coprocessor over the given transport,
+    /// such as a `TcpStream` to a remote Tessel or an in-memory `Pipe`.
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Port {
+        Port {
+            socket: Arc::new(Mutex::new(PortSocket::with_transport(Box::new(transport)))),
+            power: None,
+        }
+    }
+
+    /// Creates a port whose transport is opened by `connector`. If the
+    /// transport disconnects, for example because spid restarted, the port
+    /// calls `connector` again and restores its I2C, SPI, UART, pull,
+    /// interrupt and PWM configuration.
+    pub fn with_connector<T, F>(connector: F) -> io::Result<Port>
+        where T: Transport + 'static, F: Fn() -> io::Result<T> + Send + 'static
+    {
+        let socket = try!(PortSocket::with_connector(Box::new(move || {
+            connector().map(|transport| Box::new(transport) as Box<Transport>)
+        })));
+        Ok(Port {
+            socket: Arc::new(Mutex::new(socket)),
+            power: None,
+        })
+    }
+
+    /// Returns a channel that receives an event whenever the port has
+    /// reconnected to its coprocessor.
+    /// # Example
+    /// ```rust,no_run
+    /// use std::thread;
+    /// use tessel::Tessel;
+    ///
+    /// let (port_a, _) = Tessel::ports().unwrap();
+    /// let resets = port_a.reconnections();
+    /// thread::spawn(move || {
+    ///     for event in resets {
+    ///         println!("Port A reconnected after {} attempts.", event.attempts);
+    ///     }
+    /// });
+    /// ```
+    pub fn reconnections(&self) -> Receiver<Reconnected> {
+        self.socket.lock().unwrap().reconnections()
+    }
+
+    /// Sets the switch used by `power`. Ports from `Tessel::ports()` use
+    /// this Tessel's port power GPIOs, unless they are on a remote bridge.
+    pub fn set_power_switch(&mut self, switch: PowerSwitch) {
+        self.power = Some(switch);
+    }
+
+    /// Turns power to the module on or off. A module loses its state when
+    /// powered off, so reconnect its driver after powering it back on.
+    pub fn power(&self, on: bool) -> io::Result<()> {
+        try!(self.power_switch()).set(on)
+    }
+
+    /// Returns whether the module is powered.
+    pub fn power_state(&self) -> io::Result<bool> {
+        try!(self.power_switch()).is_on()
+    }
+
+    fn power_switch(&self) -> io::Result<&PowerSwitch> {
+        self.power.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound,
+                           "The port has no power switch; set TESSEL_PORT_A_POWER or TESSEL_PORT_B_POWER.")
+        })
+    }
+
+    /// Returns a snapshot of the port's command, byte, error and latency
+    /// counters.
+    pub fn stats(&self) -> stats::Stats {
+        self.socket.lock().unwrap().stats()
+    }
+
+    /// Sends the port's I2C, SPI, UART, pull, interrupt and PWM
+    /// configuration to the coprocessor again, for example after it was
+    /// reset through another port.
+    pub fn restore(&self) -> io::Result<()> {
+        self.socket.lock().unwrap().restore()
+    }
+
+    /// Sets how long operations on this port wait for a reply from the
+    /// coprocessor before failing with `ErrorKind::TimedOut`. `None`, the
+    /// default, waits forever. Individual I2C and SPI handles can override it.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.socket.lock().unwrap().set_timeout(timeout);
+    }
+
+    /// Checks that the coprocessor is responding by echoing a payload
+    /// through it, and returns the round-trip time.
+    /// # Example
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use tessel::Tessel;
+    ///
+    /// let (port_a, _) = Tessel::ports().unwrap();
+    /// port_a.set_timeout(Some(Duration::from_millis(500)));
+    /// println!("Port A round trip: {:?}", port_a.ping().unwrap());
+    /// ```
+    pub fn ping(&self) -> io::Result<Duration> {
+        let payload = [0x00, 0x55, 0xAA, 0xFF];
+        let start = Instant::now();
+        let mut sock = try!(Transaction::begin(&self.socket));
+        try!(sock.write_command(Command::Echo(&payload)));
+
+        let mut echo = [0; 5];
+        try!(sock.read_exact(&mut echo));
+        if echo[0] != reply::DATA.0 || echo[1..] != payload {
+            sock.poison();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Echo reply did not match."));
+        }
+        let elapsed = start.elapsed();
+        debug!(round_trip = ?elapsed, "ping");
+        Ok(elapsed)
+    }
+
     pub fn pins(&mut self) -> (Pin, Pin, Pin) {
         (
             Pin::new(5, self.socket.clone()),
@@ -107,6 +331,16 @@ impl Port {
         }
         (I2cPort::new(self.socket.clone()), Gpio::new(self.socket.clone(), available))
     }
+
+    /// Enables SPI on pins 2 (SCK), 3 (MISO) and 4 (MOSI). The remaining
+    /// pins can be used as chip selects for the devices on the bus.
+    pub fn spi<'b>(self) -> (SpiBus<'b>, Gpio<'b>) {
+        let mut available = BitSet::new();
+        for &i in &[0, 1, 5, 6, 7] {
+            available.insert(i);
+        }
+        (SpiBus::new(self.socket.clone()), Gpio::new(self.socket.clone(), available))
+    }
 }
 
 /// Gpio is a selection of pins.
@@ -176,6 +410,7 @@ impl<'a> PinSelect<'a> for (usize, usize, usize) {
 pub struct Pin<'a> {
     index: usize,
     socket: Arc<Mutex<PortSocket>>,
+    safe_state: Option<SafeState>,
     _phantom: PhantomData<&'a Port>,
 }
 
@@ -184,12 +419,33 @@ impl<'a> Pin<'a> {
         Pin {
             index: index,
             socket: socket,
+            safe_state: None,
             _phantom: PhantomData,
         }
     }
 
+    /// Drives the pin to `value` when it is dropped or the program is
+    /// stopped. See the `shutdown` module.
+    pub fn set_safe_state(&mut self, value: bool) {
+        if let Some(previous) = self.safe_state.take() {
+            previous.cancel();
+        }
+
+        let socket = self.socket.clone();
+        let index = self.index as u8;
+        self.safe_state = Some(shutdown::register(move || {
+            let timeout = Duration::from_millis(shutdown::LOCK_TIMEOUT_MS);
+            let mut sock = match shutdown::try_lock_for(&socket, timeout) {
+                Some(sock) => sock,
+                None => return,
+            };
+            let cmd = if value { Command::GpioHigh(index) } else { Command::GpioLow(index) };
+            let _ = sock.write_command(cmd);
+        }));
+    }
+
     pub fn output(&mut self, value: bool) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
+        let mut sock = try!(Transaction::begin(&self.socket));
         if value {
             sock.write_command(Command::GpioHigh(self.index as u8))
         } else {
@@ -206,151 +462,580 @@ impl<'a> Pin<'a> {
     }
 }
 
+/// I2C bus speeds supported by the SAMD21.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum I2cSpeed {
+    /// Standard mode, 100 kHz.
+    Standard,
+    /// Fast mode, 400 kHz.
+    Fast,
+    /// Fast-mode Plus, 1 MHz.
+    FastPlus,
+    /// Any other frequency in Hz, up to 1 MHz.
+    Custom(u32),
+}
+
+impl I2cSpeed {
+    /// The requested SCL frequency in Hz.
+    pub fn frequency(&self) -> u32 {
+        match *self {
+            I2cSpeed::Standard => 100_000,
+            I2cSpeed::Fast => 400_000,
+            I2cSpeed::FastPlus => 1_000_000,
+            I2cSpeed::Custom(frequency) => frequency,
+        }
+    }
+}
+
 /// An I2C Port.
 pub struct I2cPort<'a> {
     socket: Arc<Mutex<PortSocket>>,
+    speed: I2cSpeed,
+    rise_time_ns: u32,
+    timeout: Option<Duration>,
     _phantom: PhantomData<&'a Port>,
 }
 
 impl<'p> I2cPort<'p> {
-    // TODO: make frequency optional
     fn new<'a>(socket: Arc<Mutex<PortSocket>>) -> I2cPort<'a> {
         let mut i2c = I2cPort {
             socket: socket,
+            speed: I2cSpeed::Standard,
+            rise_time_ns: DEFAULT_SCL_RISE_TIME_NS,
+            timeout: None,
             _phantom: PhantomData,
         };
 
         // Use 100Khz as default frequency.
-        i2c.enable(I2cPort::compute_baud(100_000));
+        i2c.set_speed(I2cSpeed::Standard).unwrap();
 
         i2c
     }
 
     /// Computes the baudrate as used on the Atmel SAMD21 I2C register
     /// to set the frequency of the I2C Clock.
-    fn compute_baud(frequency: u32) -> u8 {
-        let mut intermediate: f64 = MCU_MAX_SPEED as f64 / frequency as f64;
-        intermediate = intermediate - MCU_MAX_SPEED as f64 * MCU_MAX_SCL_RISE_TIME_NS;
-        // TODO: Do not hardcode these numbers
-        intermediate = intermediate / MCU_MAGIC_DIV_FACTOR_FOR_I2C_BAUD as f64 -
-                       MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD as f64;
-
-        // Return either the intermediate value or 255
-        let low = intermediate.min(u8::max_value() as f64);
-
-        // If we have a potentially negative register value
-        // Casting as i64 because .float does not seem to work
-        if (low as i64) < u8::min_value() as i64 {
-            // Use 0 instead
-            return u8::min_value();
+    ///
+    /// The SERCOM generates `f_SCL = f_GCLK / (10 + 2 * BAUD + f_GCLK * t_rise)`,
+    /// so this solves for `BAUD`, rounding up so that the bus never runs
+    /// faster than requested.
+    fn compute_baud(frequency: u32, rise_time_ns: u32) -> io::Result<u8> {
+        if frequency == 0 || frequency > I2C_MAX_FREQUENCY {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "I2C frequency must be between 1 Hz and 1 MHz."));
+        }
+
+        let rise_cycles = MCU_MAX_SPEED as f64 * rise_time_ns as f64 * 1e-9;
+        let baud = ((MCU_MAX_SPEED as f64 / frequency as f64 - 10.0 - rise_cycles) / 2.0).ceil();
+
+        if baud < 0.0 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "I2C frequency is too fast for the SCL rise time."))
+        } else if baud > u8::max_value() as f64 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "I2C frequency is too slow for the SAMD21."))
         } else {
-            // Return the new register value
-            return low as u8;
+            Ok(baud as u8)
         }
     }
 
-    fn enable(&mut self, baud: u8) {
-        let mut sock = self.socket.lock().unwrap();
-        sock.write_command(Command::EnableI2c { baud: baud }).unwrap();
+    /// Computes the SCL frequency in Hz that a baud register value produces.
+    fn achieved_frequency(baud: u8, rise_time_ns: u32) -> u32 {
+        let rise_cycles = MCU_MAX_SPEED as f64 * rise_time_ns as f64 * 1e-9;
+        (MCU_MAX_SPEED as f64 / (10.0 + 2.0 * baud as f64 + rise_cycles)) as u32
     }
 
-    fn tx(sock: &mut MutexGuard<PortSocket>, address: u8, write_buf: &[u8]) {
-        sock.write_command(Command::Start(address<<1)).unwrap();
+    /// Sets how long reads on this bus wait for the coprocessor, overriding
+    /// the port's timeout. `None` goes back to the port's timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Frees a bus whose SDA line is held low by a device that was reset
+    /// partway through a transfer.
+    ///
+    /// Disables the I2C peripheral, clocks SCL (pin 0) up to nine times
+    /// until the device releases SDA (pin 1), sends a STOP condition by hand
+    /// and then re-enables I2C at the current speed. Fails if SDA is still
+    /// held low afterwards.
+    pub fn recover(&mut self) -> io::Result<()> {
+        let baud = try!(I2cPort::compute_baud(self.speed.frequency(), self.rise_time_ns));
+        info!("recovering I2C bus");
+        let mut sock = try!(Transaction::begin_with_timeout(&self.socket, self.timeout));
+        try!(sock.write_command(Command::DisableI2c));
+
+        let mut released = false;
+        for _ in 0..9 {
+            if try!(I2cPort::read_pin(&mut sock, I2C_SDA_PIN)) {
+                released = true;
+                break;
+            }
+            // Pulse SCL, letting the pull-up raise it so a device can stretch the clock.
+            try!(sock.write_command(Command::GpioLow(I2C_SCL_PIN)));
+            try!(sock.write_command(Command::GpioInput(I2C_SCL_PIN)));
+        }
+        if !released {
+            released = try!(I2cPort::read_pin(&mut sock, I2C_SDA_PIN));
+        }
+
+        // STOP: SDA rises while SCL is high.
+        try!(sock.write_command(Command::GpioLow(I2C_SCL_PIN)));
+        try!(sock.write_command(Command::GpioLow(I2C_SDA_PIN)));
+        try!(sock.write_command(Command::GpioInput(I2C_SCL_PIN)));
+        try!(sock.write_command(Command::GpioInput(I2C_SDA_PIN)));
+
+        try!(sock.write_command(Command::EnableI2c { baud: baud }));
+
+        if released {
+            Ok(())
+        } else {
+            warn!("I2C SDA line is still held low after recovery");
+            Err(io::Error::new(io::ErrorKind::Other, "I2C SDA line is still held low."))
+        }
+    }
+
+    // Reads the level of a pin while I2C is disabled.
+    fn read_pin(sock: &mut PortSocket, pin: u8) -> io::Result<bool> {
+        try!(sock.write_command(Command::GpioIn(pin)));
+        let mut read_byte = [0];
+        try!(sock.read_exact(&mut read_byte));
+        match read_byte[0] {
+            b if b == reply::HIGH.0 => Ok(true),
+            b if b == reply::LOW.0 => Ok(false),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply to pin read.")),
+        }
+    }
+
+    fn enable(&mut self, baud: u8) -> io::Result<()> {
+        let mut sock = try!(Transaction::begin(&self.socket));
+        sock.write_command(Command::EnableI2c { baud: baud })
+    }
+
+    fn tx(sock: &mut PortSocket, address: u8, write_buf: &[u8]) -> io::Result<()> {
+        try!(sock.write_command(Command::Start(address<<1)));
         // Write the command and data
-        sock.write_command(Command::Tx(write_buf)).unwrap();
+        sock.write_command(Command::Tx(write_buf))
     }
 
-    fn rx(sock: &mut MutexGuard<PortSocket>, address: u8, read_buf: &mut [u8]) {
-        sock.write_command(Command::Start(address << 1 | 1)).unwrap();
+    fn rx(sock: &mut PortSocket, address: u8, len: usize) -> io::Result<()> {
+        try!(sock.write_command(Command::Start(address << 1 | 1)));
         // Write the command and transfer length
-        sock.write_command(Command::Rx(read_buf.len() as u8)).unwrap();
+        write_rx(sock, len)
     }
 
-    fn stop(sock: &mut MutexGuard<PortSocket>) {
+    fn stop(sock: &mut PortSocket) -> io::Result<()> {
         // Tell I2C to send STOP condition
-        sock.write_command(Command::Stop).unwrap();
+        sock.write_command(Command::Stop)
+    }
+
+    // Reads the coprocessor's replies to a single `rx`, which were sent one
+    // per chunk of the transfer.
+    fn read_reply(sock: &mut PortSocket, read_buf: &mut [u8]) -> io::Result<OperationResult> {
+        let chunks = (read_buf.len() + MAX_TRANSFER - 1) / MAX_TRANSFER;
+        for (i, chunk) in read_buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            // TODO: this is not how async reads should be handled.
+            // Read in first byte.
+            let mut read_byte = [0];
+            try!(sock.read_exact(&mut read_byte));
+            if read_byte[0] == reply::NACK.0 {
+                debug!("device did not acknowledge");
+                sock.record_nack();
+                // The remaining chunks' replies, if the coprocessor sends
+                // any, must not be taken for the next transaction's.
+                if i + 1 < chunks {
+                    sock.poison();
+                }
+                return Ok(OperationResult::Nack);
+            }
+            if read_byte[0] != reply::DATA.0 {
+                sock.poison();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply to I2C read."));
+            }
+            // Read in data from the socket
+            try!(sock.read_exact(chunk));
+        }
+        Ok(OperationResult::Done(read_buf.len()))
     }
 
+    /// Sets the bus frequency, clamping it to what the SAMD21 can generate.
+    /// Use `set_speed` to validate the frequency and see the actual result.
     pub fn set_frequency(&mut self, frequency: u32) {
-        self.enable(I2cPort::compute_baud(frequency));
+        let slowest = I2cPort::achieved_frequency(u8::max_value(), self.rise_time_ns);
+        let frequency = frequency.max(slowest + 1).min(I2C_MAX_FREQUENCY);
+        let _ = self.set_speed(I2cSpeed::Custom(frequency));
+    }
+
+    /// Sets the bus speed and returns the SCL frequency actually achieved,
+    /// which is as close to the requested frequency as possible without
+    /// exceeding it. Fails with `InvalidInput` if the SAMD21 cannot
+    /// generate the speed.
+    /// # Example
+    /// ```rust,no_run
+    /// use tessel::{I2cSpeed, Tessel};
+    ///
+    /// let (port_a, _) = Tessel::ports().unwrap();
+    /// let (mut i2c, _) = port_a.i2c();
+    /// let hz = i2c.set_speed(I2cSpeed::Fast).unwrap();
+    /// assert!(hz <= 400_000);
+    /// ```
+    pub fn set_speed(&mut self, speed: I2cSpeed) -> io::Result<u32> {
+        let baud = try!(I2cPort::compute_baud(speed.frequency(), self.rise_time_ns));
+        try!(self.enable(baud));
+        self.speed = speed;
+        Ok(I2cPort::achieved_frequency(baud, self.rise_time_ns))
+    }
+
+    /// Returns the speed most recently set on this bus.
+    pub fn speed(&self) -> I2cSpeed {
+        self.speed
+    }
+
+    /// Sets the SCL rise time of this bus in nanoseconds and reapplies the
+    /// current speed to account for it, returning the achieved frequency.
+    /// Long cables and weak pull-ups have slower rise times.
+    pub fn set_rise_time(&mut self, rise_time_ns: u32) -> io::Result<u32> {
+        let previous = self.rise_time_ns;
+        self.rise_time_ns = rise_time_ns;
+        let speed = self.speed;
+        let result = self.set_speed(speed);
+        if result.is_err() {
+            self.rise_time_ns = previous;
+        }
+        result
     }
 
     pub fn send(&mut self, address: u8, write_buf: &[u8]) {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::tx(&mut sock, address, write_buf);
-        I2cPort::stop(&mut sock);
+        let mut sock = Transaction::begin(&self.socket).unwrap();
+        I2cPort::tx(&mut sock, address, write_buf).unwrap();
+        I2cPort::stop(&mut sock).unwrap();
     }
 
     pub fn read(&mut self, address: u8, read_buf: &mut [u8]) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::rx(&mut sock, address, read_buf);
-        I2cPort::stop(&mut sock);
-
-        // TODO: this is not how async reads should be handled.
-        // Read in first byte.
-        let mut read_byte = [0];
-        try!(sock.read_exact(&mut read_byte));
-        assert_eq!(read_byte[0], reply::DATA.0);
-        // Read in data from the socket
-        return sock.read_exact(read_buf);
+        let results = try!(self.transaction(address, &mut [Operation::Read(read_buf)]));
+        I2cPort::check(&results)
     }
 
     pub fn transfer(&mut self, address: u8, write_buf: &[u8], read_buf: &mut [u8]) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::tx(&mut sock, address, write_buf);
-        I2cPort::rx(&mut sock, address, read_buf);
-        I2cPort::stop(&mut sock);
+        let results = try!(self.transaction(address, &mut [Operation::Write(write_buf),
+                                                           Operation::Read(read_buf)]));
+        I2cPort::check(&results)
+    }
 
-        // TODO: this is not how async reads should be handled.
-        // Read in first byte.
-        let mut read_byte = [0];
-        try!(sock.read_exact(&mut read_byte));
-        assert_eq!(read_byte[0], reply::DATA.0);
-        // Read in data from the socket
-        return sock.read_exact(read_buf);
+    /// Performs a sequence of writes and reads as one atomic I2C transaction.
+    ///
+    /// Each operation begins with a (repeated) start condition addressed to
+    /// `address`, and a single stop condition ends the sequence. No other
+    /// user of the port can issue commands until it completes. Returns the
+    /// outcome of each operation, in order.
+    /// # Example
+    /// ```rust,no_run
+    /// use tessel::{Operation, Tessel};
+    ///
+    /// let (port_a, _) = Tessel::ports().unwrap();
+    /// let (mut i2c, _) = port_a.i2c();
+    ///
+    /// // Set an EEPROM's 16-bit address pointer, then read back from it.
+    /// let mut page = [0; 32];
+    /// i2c.transaction(0x50, &mut [Operation::Write(&[0x01, 0x00]),
+    ///                             Operation::Read(&mut page)]).unwrap();
+    /// ```
+    pub fn transaction(&mut self, address: u8, operations: &mut [Operation]) -> io::Result<Vec<OperationResult>> {
+        let _span = debug_span!("i2c", address).entered();
+        let mut sock = try!(Transaction::begin_with_timeout(&self.socket, self.timeout));
+        for operation in operations.iter() {
+            match *operation {
+                Operation::Write(write_buf) => try!(I2cPort::tx(&mut sock, address, write_buf)),
+                Operation::Read(ref read_buf) => try!(I2cPort::rx(&mut sock, address, read_buf.len())),
+            }
+        }
+        try!(I2cPort::stop(&mut sock));
+
+        // Replies arrive in the same order as the reads were issued.
+        operations.iter_mut().map(|operation| {
+            match *operation {
+                Operation::Write(write_buf) => Ok(OperationResult::Done(write_buf.len())),
+                Operation::Read(ref mut read_buf) => I2cPort::read_reply(&mut sock, read_buf),
+            }
+        }).collect()
+    }
+
+    // Turns a NACK from any operation into an error.
+    fn check(results: &[OperationResult]) -> io::Result<()> {
+        if results.contains(&OperationResult::Nack) {
+            return Err(io::Error::new(io::ErrorKind::Other, "I2C device did not acknowledge."));
+        }
+        Ok(())
+    }
+}
+
+/// A single step of an `I2cPort::transaction`.
+pub enum Operation<'a> {
+    /// Write these bytes to the device.
+    Write(&'a [u8]),
+    /// Fill this buffer with bytes read from the device.
+    Read(&'a mut [u8]),
+}
+
+/// Outcome of one `Operation` in an I2C transaction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OperationResult {
+    /// The operation completed, transferring this many bytes.
+    Done(usize),
+    /// The device did not acknowledge the read.
+    Nack,
+}
+
+/// SPI clock polarity and phase.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpiMode {
+    /// Clock idles low, data sampled on the rising edge.
+    Mode0 = 0,
+    /// Clock idles low, data sampled on the falling edge.
+    Mode1 = 1,
+    /// Clock idles high, data sampled on the falling edge.
+    Mode2 = 2,
+    /// Clock idles high, data sampled on the rising edge.
+    Mode3 = 3,
+}
+
+// Settings programmed into the SPI peripheral by `Command::EnableSpi`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct SpiConfig {
+    mode: u8,
+    baud: u8,
+    div: u8,
+}
+
+impl SpiConfig {
+    /// Computes the baud register and clock divider for a frequency, using
+    /// the smallest divider that keeps the baud register in range.
+    fn new(mode: SpiMode, frequency: u32) -> io::Result<SpiConfig> {
+        if frequency < SPI_MIN_FREQUENCY || frequency > SPI_MAX_FREQUENCY {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "SPI frequency must be between 368 Hz and 24 MHz."));
+        }
+
+        let mut baud = MCU_MAX_SPEED / (2 * frequency) - 1;
+        let mut div = 1;
+        if baud > 255 {
+            // The clock is F / (2 * div * (baud + 1)), with baud + 1 at most 256.
+            div = (MCU_MAX_SPEED + frequency * 512 - 1) / (frequency * 512);
+            baud = MCU_MAX_SPEED / (2 * frequency * div) - 1;
+        }
+
+        Ok(SpiConfig {
+            mode: mode as u8,
+            baud: baud as u8,
+            div: div as u8,
+        })
+    }
+}
+
+/// The SPI peripheral of a port, shared by any number of `SpiDevice`s.
+pub struct SpiBus<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    // Configuration currently programmed into the peripheral.
+    active: Arc<Mutex<Option<SpiConfig>>>,
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'p> SpiBus<'p> {
+    fn new<'a>(socket: Arc<Mutex<PortSocket>>) -> SpiBus<'a> {
+        SpiBus {
+            socket: socket,
+            active: Arc::new(Mutex::new(None)),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Creates a handle for a device on this bus, selected by pulling `cs`
+    /// low and clocked with its own mode and frequency.
+    /// # Example
+    /// ```rust,no_run
+    /// use tessel::{SpiMode, Tessel};
+    ///
+    /// let (port_a, _) = Tessel::ports().unwrap();
+    /// let (bus, gpio) = port_a.spi();
+    /// let (flash_cs, adc_cs) = gpio.pin_select((5, 6));
+    ///
+    /// let mut flash = bus.device(flash_cs, SpiMode::Mode0, 12_000_000).unwrap();
+    /// let mut adc = bus.device(adc_cs, SpiMode::Mode1, 1_000_000).unwrap();
+    ///
+    /// let mut id = [0; 4];
+    /// flash.transfer(&[0x9F, 0, 0, 0], &mut id).unwrap();
+    /// let mut sample = [0; 2];
+    /// adc.receive(&mut sample).unwrap();
+    /// ```
+    pub fn device<'b>(&self, cs: Pin<'b>, mode: SpiMode, frequency: u32) -> io::Result<SpiDevice<'b>> {
+        let config = try!(SpiConfig::new(mode, frequency));
+        let mut device = SpiDevice {
+            socket: self.socket.clone(),
+            active: self.active.clone(),
+            cs: cs,
+            config: config,
+            timeout: None,
+        };
+        // Deselect the device until it is used.
+        try!(device.cs.high());
+        Ok(device)
+    }
+}
+
+/// A device on an `SpiBus`, with its own chip select and clock settings.
+pub struct SpiDevice<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    active: Arc<Mutex<Option<SpiConfig>>>,
+    cs: Pin<'a>,
+    config: SpiConfig,
+    timeout: Option<Duration>,
+}
+
+impl<'a> SpiDevice<'a> {
+    /// Sets how long transfers with this device wait for the coprocessor,
+    /// overriding the port's timeout. `None` goes back to the port's timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Writes `write_buf` while reading the same number of bytes into `read_buf`.
+    pub fn transfer(&mut self, write_buf: &[u8], read_buf: &mut [u8]) -> io::Result<()> {
+        if write_buf.len() != read_buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "SPI transfer buffers must be the same length."));
+        }
+        let _span = debug_span!("spi", chip_select = self.cs.index).entered();
+        let mut sock = try!(self.select());
+        try!(sock.write_command(Command::TxRx(write_buf)));
+        try!(sock.write_command(Command::GpioHigh(self.cs.index as u8)));
+        SpiDevice::read_reply(&mut sock, read_buf)
+    }
+
+    /// Writes `write_buf`, discarding whatever the device sends back.
+    pub fn send(&mut self, write_buf: &[u8]) -> io::Result<()> {
+        let _span = debug_span!("spi", chip_select = self.cs.index).entered();
+        let mut sock = try!(self.select());
+        try!(sock.write_command(Command::Tx(write_buf)));
+        sock.write_command(Command::GpioHigh(self.cs.index as u8))
+    }
+
+    /// Reads into `read_buf` while clocking out zeroes.
+    pub fn receive(&mut self, read_buf: &mut [u8]) -> io::Result<()> {
+        let _span = debug_span!("spi", chip_select = self.cs.index).entered();
+        let mut sock = try!(self.select());
+        try!(write_rx(&mut sock, read_buf.len()));
+        try!(sock.write_command(Command::GpioHigh(self.cs.index as u8)));
+        SpiDevice::read_reply(&mut sock, read_buf)
+    }
+
+    // Takes the bus, reconfigures it for this device if another device used
+    // it last, and asserts chip select.
+    fn select(&self) -> io::Result<Transaction> {
+        let mut sock = try!(Transaction::begin_with_timeout(&self.socket, self.timeout));
+        {
+            let mut active = self.active.lock().unwrap();
+            if *active != Some(self.config) {
+                try!(sock.write_command(Command::EnableSpi {
+                    mode: self.config.mode,
+                    freq: self.config.baud,
+                    div: self.config.div,
+                }));
+                *active = Some(self.config);
+            }
+        }
+        try!(sock.write_command(Command::GpioLow(self.cs.index as u8)));
+        Ok(sock)
+    }
+
+    // Reads the replies to a transfer, which were sent one per chunk.
+    fn read_reply(sock: &mut PortSocket, read_buf: &mut [u8]) -> io::Result<()> {
+        for chunk in read_buf.chunks_mut(MAX_TRANSFER) {
+            let mut read_byte = [0];
+            try!(sock.read_exact(&mut read_byte));
+            if read_byte[0] != reply::DATA.0 {
+                sock.poison();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply to SPI transfer."));
+            }
+            try!(sock.read_exact(chunk));
+        }
+        Ok(())
+    }
+}
+
+// Asks for `len` bytes, with one `Rx` command per `MAX_TRANSFER` bytes.
+fn write_rx(sock: &mut PortSocket, len: usize) -> io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_TRANSFER);
+        try!(sock.write_command(Command::Rx(chunk as u8)));
+        remaining -= chunk;
     }
+    Ok(())
+}
+
+/// Directory holding the Tessel 2's LEDs in sysfs.
+pub const LED_SYSFS_PATH: &'static str = "/sys/devices/leds/leds";
+
+/// A kernel trigger that drives an LED without help from the program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// The LED only changes when the program sets it.
+    None,
+    /// Blinks with the given on and off times, at millisecond resolution.
+    Timer { delay_on: Duration, delay_off: Duration },
+    /// Double-flashes at a rate following the system load.
+    Heartbeat,
 }
 
-// TODO: Figure out how to override the path secretly so the example
-// can actually be run.
 /// A LED models an LED on the Tessel board.
 /// # Example
 /// ```rust,no_run
-/// use tessel::LED;
+/// use std::time::Duration;
+/// use tessel::{LED, Trigger};
 ///
 /// let mut led = LED::new("red", "error");
 /// // LEDs are off by default.
 /// assert_eq!(false, led.read());
 /// led.on().unwrap();
 /// assert_eq!(true, led.read());
+///
+/// // Let the kernel blink the LED.
+/// led.set_trigger(Trigger::Timer {
+///     delay_on: Duration::from_millis(100),
+///     delay_off: Duration::from_millis(900),
+/// }).unwrap();
+/// ```
 pub struct LED {
-    // The file object we write to in order to change state.
-    file: File,
-    // The current value of the LED, defaults to false.
+    // The LED's sysfs directory, holding `brightness`, `trigger` and so on.
+    dir: PathBuf,
+    max_brightness: u32,
+    // Whether the program last turned the LED on.
     value: bool,
+    safe_state: Option<SafeState>,
 }
 
 impl LED {
     pub fn new(color: &'static str, kind: &'static str) -> LED {
-        let path = format!("/sys/devices/leds/leds/tessel:{}:{}/brightness",
-                           color,
-                           kind);
-
-        // Open the file for write operations.
-        LED::new_with_file(File::create(path).unwrap())
+        let path = Path::new(LED_SYSFS_PATH).join(format!("tessel:{}:{}", color, kind));
+        LED::with_dir(path).unwrap()
     }
 
-
-    fn new_with_file(file: File) -> LED {
+    /// Creates a LED from its sysfs directory, which may be a fake one.
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> Result<LED, io::Error> {
+        let dir = dir.as_ref().to_path_buf();
+        let max_brightness = match try!(read_attribute(&dir, "max_brightness")).trim().parse() {
+            Ok(max) => max,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid LED max_brightness.")),
+        };
         let mut led = LED {
+            dir: dir,
+            max_brightness: max_brightness,
             value: false,
-            file: file,
+            safe_state: None,
         };
 
         // Turn the LED off by default.
-        led.off().unwrap();
+        try!(led.off());
 
-        led
+        Ok(led)
     }
 
     // Turn the LED on (same as `high`).
@@ -365,62 +1050,517 @@ impl LED {
 
     // Turn the LED on.
     pub fn high(&mut self) -> Result<(), io::Error> {
-        self.write(true)
+        let max = self.max_brightness;
+        self.set_brightness(max)
     }
 
     // Turn the LED off.
     pub fn low(&mut self) -> Result<(), io::Error> {
-        self.write(false)
+        self.set_brightness(0)
     }
 
     // Sets the LED to the opposite of its current state.
     pub fn toggle(&mut self) -> Result<(), io::Error> {
-        let new_value = !self.value;
-        self.write(new_value)
+        if try!(self.brightness()) > 0 {
+            self.low()
+        } else {
+            self.high()
+        }
     }
 
-    // Returns the current state of the LED.
+    /// Returns whether the program last turned the LED on. `brightness`
+    /// reads the kernel's view, which also follows triggers.
     pub fn read(&self) -> bool {
         self.value
     }
 
-    // Helper function to write new state to LED filepath.
-    fn write(&mut self, new_value: bool) -> Result<(), io::Error> {
-        // Save the new value to the model.
-        self.value = new_value;
-        // Return the binary representation of that value type.
-        let string_value = match new_value {
-            true => b'1',
-            false => b'0',
-        };
+    /// Returns the brightest level the LED supports.
+    pub fn max_brightness(&self) -> u32 {
+        self.max_brightness
+    }
 
-        // Write that data to the file and return the result.
-        self.file.write_all(&[string_value])
+    /// Returns the LED's current brightness level.
+    pub fn brightness(&self) -> Result<u32, io::Error> {
+        try!(read_attribute(&self.dir, "brightness")).trim().parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid LED brightness."))
+    }
+
+    /// Sets the brightness level, limited to `max_brightness`. Setting it
+    /// to 0 also stops any trigger.
+    pub fn set_brightness(&mut self, level: u32) -> Result<(), io::Error> {
+        try!(write_attribute(&self.dir, "brightness", &level.min(self.max_brightness).to_string()));
+        self.value = level > 0;
+        Ok(())
+    }
+
+    /// Returns the name of the active kernel trigger.
+    pub fn trigger(&self) -> Result<String, io::Error> {
+        // The kernel lists every trigger and brackets the active one.
+        let triggers = try!(read_attribute(&self.dir, "trigger"));
+        triggers.split_whitespace()
+            .find(|t| t.starts_with('[') && t.ends_with(']'))
+            .map(|t| t[1..t.len() - 1].to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No active LED trigger."))
+    }
+
+    /// Hands the LED to a kernel trigger, so it can blink without the
+    /// program waking up.
+    pub fn set_trigger(&mut self, trigger: Trigger) -> Result<(), io::Error> {
+        match trigger {
+            Trigger::None => write_attribute(&self.dir, "trigger", "none"),
+            Trigger::Heartbeat => write_attribute(&self.dir, "trigger", "heartbeat"),
+            Trigger::Timer { delay_on, delay_off } => {
+                // The delay files only appear once the timer trigger is active.
+                try!(write_attribute(&self.dir, "trigger", "timer"));
+                try!(write_attribute(&self.dir, "delay_on", &duration_ms(delay_on).to_string()));
+                write_attribute(&self.dir, "delay_off", &duration_ms(delay_off).to_string())
+            }
+        }
+    }
+
+    /// Sets the LED to `on` when it is dropped or the program is stopped.
+    /// See the `shutdown` module.
+    pub fn set_safe_state(&mut self, on: bool) -> Result<(), io::Error> {
+        if let Some(previous) = self.safe_state.take() {
+            previous.cancel();
+        }
+
+        let dir = self.dir.clone();
+        let level = if on { self.max_brightness } else { 0 };
+        self.safe_state = Some(shutdown::register(move || {
+            let _ = write_attribute(&dir, "trigger", "none");
+            let _ = write_attribute(&dir, "brightness", &level.to_string());
+        }));
+        Ok(())
     }
 }
 
+// Reads a sysfs attribute of the LED in `dir`.
+fn read_attribute(dir: &Path, name: &str) -> Result<String, io::Error> {
+    let mut value = String::new();
+    try!(try!(File::open(dir.join(name))).read_to_string(&mut value));
+    Ok(value)
+}
+
+// Replaces a sysfs attribute of the LED in `dir`. Each value is written
+// from the start of a freshly opened file, as sysfs expects.
+fn write_attribute(dir: &Path, name: &str, value: &str) -> Result<(), io::Error> {
+    let mut file = try!(OpenOptions::new().write(true).truncate(true).open(dir.join(name)));
+    file.write_all(value.as_bytes())
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
     use super::*;
-    use std::io::{Read, Seek, SeekFrom};
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use protocol::reply;
+    use self::tempfile::TempDir;
+
+    // Creates a fake sysfs LED directory.
+    fn fake_led() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for &(attribute, value) in &[("brightness", "1\n"),
+                                     ("max_brightness", "255\n"),
+                                     ("trigger", "none timer [heartbeat]\n"),
+                                     ("delay_on", ""),
+                                     ("delay_off", "")] {
+            File::create(dir.path().join(attribute)).unwrap().write_all(value.as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    fn attribute(dir: &Path, name: &str) -> String {
+        let mut value = String::new();
+        File::open(dir.join(name)).unwrap().read_to_string(&mut value).unwrap();
+        value
+    }
 
     #[test]
-    fn led_writes_to_file() {
-        let mut tmpfile = tempfile::tempfile().unwrap();
-        // The tmpfile handle can be reused as long as LED gets its own
-        // clone of the handle, and we are diligent about seeking.
-        // This avoids needing to figure out where the tmpfile is in order
-        // to open more handles.
-        let mut led = LED::new_with_file(tmpfile.try_clone().unwrap());
-        let mut buf = String::new();
-        tmpfile.seek(SeekFrom::Start(0)).unwrap();
-        tmpfile.read_to_string(&mut buf).unwrap();
-        assert_eq!("0", buf);
+    fn led_rewrites_brightness() {
+        let fake = fake_led();
+        let dir = fake.path();
+        let mut led = LED::with_dir(dir).unwrap();
+        assert_eq!("0", attribute(dir, "brightness"));
+        assert!(!led.read());
+
         led.on().unwrap();
-        tmpfile.seek(SeekFrom::Start(0)).unwrap();
-        tmpfile.read_to_string(&mut buf).unwrap();
-        // b'1' is written as 001 into the file.
-        assert_eq!("001", buf);
+        assert_eq!("255", attribute(dir, "brightness"));
+        assert!(led.read());
+        led.toggle().unwrap();
+        assert!(!led.read());
+        assert_eq!("0", attribute(dir, "brightness"));
+
+        led.set_brightness(1000).unwrap();
+        assert_eq!(255, led.brightness().unwrap());
+
+        // Changes made outside the program are read back.
+        File::create(dir.join("brightness")).unwrap().write_all(b"12\n").unwrap();
+        assert_eq!(12, led.brightness().unwrap());
+    }
+
+    #[test]
+    fn led_triggers() {
+        let fake = fake_led();
+        let dir = fake.path();
+        let mut led = LED::with_dir(dir).unwrap();
+        assert_eq!("heartbeat", led.trigger().unwrap());
+
+        led.set_trigger(Trigger::Timer {
+            delay_on: Duration::from_millis(100),
+            delay_off: Duration::from_secs(2),
+        }).unwrap();
+        assert_eq!("timer", attribute(dir, "trigger"));
+        assert_eq!("100", attribute(dir, "delay_on"));
+        assert_eq!("2000", attribute(dir, "delay_off"));
+
+        led.set_trigger(Trigger::None).unwrap();
+        assert_eq!("none", attribute(dir, "trigger"));
+    }
+
+    #[test]
+    fn port_power_switch() {
+        let fake = tempfile::tempdir().unwrap();
+        let root = fake.path();
+        let path = root.join("port_a_power");
+        File::create(&path).unwrap().write_all(b"1\n").unwrap();
+
+        let (local, _remote) = transport::pipe();
+        let mut port = Port::with_transport(local);
+        assert_eq!(io::ErrorKind::NotFound, port.power(false).unwrap_err().kind());
+
+        port.set_power_switch(PowerSwitch::new(&path));
+        assert!(port.power_state().unwrap());
+        port.power(false).unwrap();
+        assert!(!port.power_state().unwrap());
+    }
+
+    #[test]
+    fn usb_power_path_from_environment() {
+        let fake = tempfile::tempdir().unwrap();
+        let root = fake.path();
+        File::create(root.join("usb_enable")).unwrap().write_all(b"0\n").unwrap();
+
+        assert_eq!(io::ErrorKind::NotFound, PowerSwitch::usb(root).unwrap_err().kind());
+        env::set_var(power::USB_POWER_VAR, "/usb_enable");
+        let switch = PowerSwitch::usb(root);
+        env::remove_var(power::USB_POWER_VAR);
+        assert!(!switch.unwrap().is_on().unwrap());
+    }
+
+    #[test]
+    fn port_stats_count_traffic() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        port.set_timeout(Some(Duration::from_millis(10)));
+        let socket = port.socket.clone();
+        let (mut i2c, _) = port.i2c();
+
+        remote.write_all(&[reply::DATA.0, 0x2A, reply::NACK.0]).unwrap();
+        let mut buf = [0; 1];
+        i2c.read(0x1D, &mut buf).unwrap();
+        assert!(i2c.read(0x1D, &mut buf).is_err());
+        assert_eq!(io::ErrorKind::TimedOut, i2c.read(0x1D, &mut buf).unwrap_err().kind());
+
+        let stats = socket.lock().unwrap().stats();
+        assert_eq!(Some(&1), stats.commands.get("EnableI2c"));
+        assert_eq!(Some(&3), stats.commands.get("Rx"));
+        assert_eq!(2 + 3 * 5, stats.bytes_out);
+        assert_eq!(3, stats.bytes_in);
+        assert_eq!(1, stats.nacks);
+        assert_eq!(1, stats.timeouts);
+        assert_eq!(2, stats.latency.count());
+    }
+
+    #[test]
+    fn late_reply_after_timeout_is_discarded() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        port.set_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(io::ErrorKind::TimedOut, port.ping().unwrap_err().kind());
+
+        let echo = [reply::DATA.0, 0x00, 0x55, 0xAA, 0xFF];
+        let mut sent = [0; 6];
+        remote.read_exact(&mut sent).unwrap();
+        remote.write_all(&echo).unwrap();
+
+        let coprocessor = thread::spawn(move || {
+            remote.read_exact(&mut sent).unwrap();
+            remote.write_all(&echo).unwrap();
+            remote
+        });
+        port.set_timeout(Some(Duration::from_secs(5)));
+        port.ping().unwrap();
+        coprocessor.join().unwrap();
+    }
+
+    #[test]
+    fn nack_in_long_read_discards_later_replies() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+        let mut enable = [0; 2];
+        remote.read_exact(&mut enable).unwrap();
+
+        remote.write_all(&[reply::NACK.0]).unwrap();
+        let mut buf = [0; 300];
+        assert!(i2c.read(0x1D, &mut buf).is_err());
+        // The coprocessor answers the second chunk after all.
+        remote.write_all(&[reply::DATA.0; 46]).unwrap();
+
+        let coprocessor = thread::spawn(move || {
+            // START, RX x2, STOP, then the next read's START, RX, STOP.
+            let mut sent = [0; 7 + 5];
+            remote.read_exact(&mut sent).unwrap();
+            remote.write_all(&[reply::DATA.0, 0x2A]).unwrap();
+            remote
+        });
+        let mut buf = [0; 1];
+        i2c.read(0x1D, &mut buf).unwrap();
+        assert_eq!([0x2A], buf);
+        coprocessor.join().unwrap();
+    }
+
+    #[test]
+    fn i2c_read_over_pipe() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+
+        // Queue up the coprocessor's reply before issuing the read.
+        remote.write_all(&[reply::DATA.0, 0x2A]).unwrap();
+        let mut buf = [0; 1];
+        i2c.read(0x1D, &mut buf).unwrap();
+        assert_eq!([0x2A], buf);
+
+        let mut sent = [0; 7];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x0C, I2cPort::compute_baud(100_000, DEFAULT_SCL_RISE_TIME_NS).unwrap(),
+                    0x13, 0x1D << 1 | 1,
+                    0x11, 1,
+                    0x14], sent);
+    }
+
+    #[test]
+    fn i2c_transaction_uses_repeated_starts() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+        let mut enable = [0; 2];
+        remote.read_exact(&mut enable).unwrap();
+
+        remote.write_all(&[reply::DATA.0, 0xAA, 0xBB, reply::NACK.0]).unwrap();
+        let mut first = [0; 2];
+        let mut second = [0; 1];
+        let results = i2c.transaction(0x50, &mut [Operation::Write(&[0x01, 0x00]),
+                                                  Operation::Read(&mut first),
+                                                  Operation::Read(&mut second)]).unwrap();
+        assert_eq!(vec![OperationResult::Done(2), OperationResult::Done(2), OperationResult::Nack], results);
+        assert_eq!([0xAA, 0xBB], first);
+
+        let mut sent = [0; 15];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x13, 0x50 << 1, 0x10, 2, 0x01, 0x00,
+                    0x13, 0x50 << 1 | 1, 0x11, 2,
+                    0x13, 0x50 << 1 | 1, 0x11, 1,
+                    0x14], sent);
+    }
+
+    #[test]
+    fn i2c_read_longer_than_a_command() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+        let mut enable = [0; 2];
+        remote.read_exact(&mut enable).unwrap();
+
+        // Each chunk of the read gets its own reply.
+        remote.write_all(&[reply::DATA.0]).unwrap();
+        remote.write_all(&[0x11; 255]).unwrap();
+        remote.write_all(&[reply::DATA.0]).unwrap();
+        remote.write_all(&[0x22; 45]).unwrap();
+        let mut buf = [0; 300];
+        i2c.read(0x50, &mut buf).unwrap();
+        assert!(buf[..255].iter().all(|&b| b == 0x11));
+        assert!(buf[255..].iter().all(|&b| b == 0x22));
+
+        let mut sent = [0; 7];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x13, 0x50 << 1 | 1, 0x11, 255, 0x11, 45, 0x14], sent);
+    }
+
+    #[test]
+    fn i2c_speeds() {
+        let rise = DEFAULT_SCL_RISE_TIME_NS;
+        assert_eq!(235, I2cPort::compute_baud(I2cSpeed::Standard.frequency(), rise).unwrap());
+        assert_eq!(55, I2cPort::compute_baud(I2cSpeed::Fast.frequency(), rise).unwrap());
+        assert_eq!(19, I2cPort::compute_baud(I2cSpeed::FastPlus.frequency(), rise).unwrap());
+        assert_eq!(99_850, I2cPort::achieved_frequency(235, rise));
+
+        // Out of range for the SAMD21.
+        assert!(I2cPort::compute_baud(50_000, rise).is_err());
+        assert!(I2cPort::compute_baud(2_000_000, rise).is_err());
+        // A slow rise time leaves no room for Fast-mode Plus.
+        assert!(I2cPort::compute_baud(1_000_000, 1000).is_err());
+    }
+
+    #[test]
+    fn i2c_set_speed_reports_achieved_frequency() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+
+        assert_eq!(397_614, i2c.set_speed(I2cSpeed::Fast).unwrap());
+        assert_eq!(I2cSpeed::Fast, i2c.speed());
+        assert_eq!(398_671, i2c.set_rise_time(300).unwrap());
+        assert!(i2c.set_speed(I2cSpeed::Custom(10_000)).is_err());
+        assert_eq!(I2cSpeed::Fast, i2c.speed());
+
+        let mut sent = [0; 6];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x0C, 235, 0x0C, 55, 0x0C, 48], sent);
+
+        // A lost port socket is an error, not a panic.
+        drop(remote);
+        assert!(i2c.set_speed(I2cSpeed::Standard).is_err());
+        assert!(i2c.set_rise_time(1000).is_err());
+        assert_eq!(I2cSpeed::Fast, i2c.speed());
+    }
+
+    #[test]
+    fn i2c_recover_clocks_until_sda_released() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (mut i2c, _) = port.i2c();
+        let mut enable = [0; 2];
+        remote.read_exact(&mut enable).unwrap();
+
+        remote.write_all(&[reply::LOW.0, reply::LOW.0, reply::HIGH.0]).unwrap();
+        i2c.recover().unwrap();
+
+        let mut sent = [0; 25];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x0D,
+                    0x03, 1, 0x05, 0, 0x16, 0,
+                    0x03, 1, 0x05, 0, 0x16, 0,
+                    0x03, 1,
+                    0x05, 0, 0x05, 1, 0x16, 0, 0x16, 1,
+                    0x0C, enable[1]], sent);
+    }
+
+    #[test]
+    fn spi_config() {
+        assert_eq!(SpiConfig { mode: 0, baud: 23, div: 1 },
+                   SpiConfig::new(SpiMode::Mode0, 1_000_000).unwrap());
+        assert_eq!(SpiConfig { mode: 3, baud: 254, div: 94 },
+                   SpiConfig::new(SpiMode::Mode3, 1_000).unwrap());
+        assert_eq!(SpiConfig { mode: 0, baud: 254, div: 255 },
+                   SpiConfig::new(SpiMode::Mode0, 368).unwrap());
+        assert!(SpiConfig::new(SpiMode::Mode0, 100).is_err());
+        assert!(SpiConfig::new(SpiMode::Mode0, 48_000_000).is_err());
+    }
+
+    #[test]
+    fn spi_devices_share_bus() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        let (bus, gpio) = port.spi();
+        let (cs1, cs2) = gpio.pin_select((5, 6));
+        let mut first = bus.device(cs1, SpiMode::Mode0, 1_000_000).unwrap();
+        let mut second = bus.device(cs2, SpiMode::Mode3, 4_000_000).unwrap();
+
+        remote.write_all(&[reply::DATA.0, 0xAA, reply::DATA.0, 0xBB, reply::DATA.0, 0xCC]).unwrap();
+        let mut buf = [0; 1];
+        first.transfer(&[0x01], &mut buf).unwrap();
+        assert_eq!([0xAA], buf);
+        first.transfer(&[0x02], &mut buf).unwrap();
+        assert_eq!([0xBB], buf);
+        second.receive(&mut buf).unwrap();
+        assert_eq!([0xCC], buf);
+
+        let mut sent = [0; 32];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x04, 5, 0x04, 6,
+                    // The first transfer configures the bus for the first device.
+                    0x0A, 0, 23, 1, 0x05, 5, 0x12, 1, 0x01, 0x04, 5,
+                    // The second transfer reuses the configuration.
+                    0x05, 5, 0x12, 1, 0x02, 0x04, 5,
+                    // Switching devices reconfigures the bus.
+                    0x0A, 3, 5, 1, 0x05, 6, 0x11, 1, 0x04, 6], sent);
+    }
+
+    #[test]
+    fn i2c_read_times_out() {
+        let (local, _remote) = transport::pipe();
+        let port = Port::with_transport(local);
+        port.set_timeout(Some(Duration::from_secs(10)));
+        let (mut i2c, _) = port.i2c();
+        i2c.set_timeout(Some(Duration::from_millis(20)));
+
+        // The coprocessor never answers.
+        let mut buf = [0; 1];
+        assert_eq!(io::ErrorKind::TimedOut, i2c.read(0x1D, &mut buf).unwrap_err().kind());
+    }
+
+    #[test]
+    fn ping_checks_echo() {
+        let (local, mut remote) = transport::pipe();
+        let port = Port::with_transport(local);
+
+        remote.write_all(&[reply::DATA.0, 0x00, 0x55, 0xAA, 0xFF]).unwrap();
+        port.ping().unwrap();
+        let mut sent = [0; 6];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x02, 4, 0x00, 0x55, 0xAA, 0xFF], sent);
+
+        remote.write_all(&[reply::DATA.0, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, port.ping().unwrap_err().kind());
+    }
+
+    #[test]
+    fn port_reconnects_and_replays_configuration() {
+        let (first, first_remote) = transport::pipe();
+        let (second, mut second_remote) = transport::pipe();
+        let transports = Mutex::new(vec![second, first]);
+        let port = Port::with_connector(move || {
+            transports.lock().unwrap().pop().ok_or(io::Error::new(io::ErrorKind::NotFound, "No more pipes."))
+        }).unwrap();
+        let reconnections = port.reconnections();
+        let (mut i2c, _) = port.i2c();
+
+        // spid goes away and comes back. The interrupted transaction fails
+        // rather than finishing on the new connection without its START.
+        drop(first_remote);
+        let error = i2c.transaction(0x1D, &mut [Operation::Write(&[0x2A])]).unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionReset, error.kind());
+        assert_eq!(Reconnected { attempts: 1 }, reconnections.try_recv().unwrap());
+
+        i2c.send(0x1D, &[0x2A]);
+        let mut sent = [0; 8];
+        second_remote.read_exact(&mut sent).unwrap();
+        let baud = I2cPort::compute_baud(100_000, DEFAULT_SCL_RISE_TIME_NS).unwrap();
+        assert_eq!([0x0C, baud, 0x13, 0x1D << 1, 0x10, 1, 0x2A, 0x14], sent);
+    }
+
+    #[test]
+    fn pin_safe_state_applied_on_drop() {
+        let (local, mut remote) = transport::pipe();
+        let mut port = Port::with_transport(local);
+        let (mut pin, _, _) = port.pins();
+
+        pin.set_safe_state(true);
+        pin.set_safe_state(false);
+        pin.high().unwrap();
+        drop(pin);
+
+        let mut sent = [0; 4];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([0x04, 5, 0x05, 5], sent);
     }
 }
@@ -2,28 +2,37 @@
 
 extern crate tessel;
 
-use tessel::Tessel;
-use std::thread::sleep;
+use tessel::{shutdown, Tessel, Trigger};
+use std::thread;
 use std::time::Duration;
 
 fn main() {
+    // Turn the LEDs off again on CTRL + C.
+    shutdown::handle_signals().unwrap();
+
     // Create a new Tessel
     let mut tessel = Tessel::new();
 
     // Attempt to acquire Tessel ports.
     let (_a, _b) = Tessel::ports().expect("Could not acquire Tessel ports.");
 
-    // Turn on one of the LEDs
-    tessel.led[2].on().unwrap();
+    tessel.led[2].set_safe_state(false).unwrap();
+    tessel.led[3].set_safe_state(false).unwrap();
+
+    // Let the kernel blink both LEDs, each at its own rate.
+    tessel.led[2].set_trigger(Trigger::Timer {
+        delay_on: Duration::from_millis(100),
+        delay_off: Duration::from_millis(100),
+    }).unwrap();
+    tessel.led[3].set_trigger(Trigger::Timer {
+        delay_on: Duration::from_millis(100),
+        delay_off: Duration::from_millis(300),
+    }).unwrap();
 
     println!("I'm blinking! (Press CTRL + C to stop)");
 
-    // Loop forever
+    // Nothing left to do but wait for CTRL + C.
     loop {
-        // Toggle each LED
-        tessel.led[2].toggle().unwrap();
-        tessel.led[3].toggle().unwrap();
-        // Re-execute the loop after sleeping for 100ms
-        sleep(Duration::from_millis(100));
+        thread::park();
     }
 }
@@ -0,0 +1,502 @@
+//! WiFi and network configuration, stored in OpenWrt's UCI files.
+//!
+//! The Tessel keeps its network settings in `/etc/config/wireless` and
+//! `/etc/config/network`. `Network` reads and rewrites those files directly,
+//! relative to a root directory that defaults to `/` and can point at a
+//! fixture tree instead. Changes are saved to disk only; they take effect
+//! once the network is reloaded, for example by running `wifi`. As with
+//! `uci commit`, saving rewrites the whole file, so comments in it are lost.
+//! # Example
+//! ```rust,no_run
+//! use tessel::network::{Network, Security};
+//!
+//! let network = Network::new();
+//! network.set_station("HomeNetwork", Some("correct horse"), Security::Psk2).unwrap();
+//! network.set_access_point_enabled(false).unwrap();
+//! ```
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+const WIRELESS_PATH: &'static str = "etc/config/wireless";
+const NETWORK_PATH: &'static str = "etc/config/network";
+
+/// The value of a UCI option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// An `option` line.
+    Single(String),
+    /// The values of one or more `list` lines.
+    List(Vec<String>),
+}
+
+/// A `config` section of a UCI file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub kind: String,
+    /// The section name, or `None` for an anonymous section.
+    pub name: Option<String>,
+    pub options: Vec<(String, Value)>,
+}
+
+impl Section {
+    pub fn new(kind: &str, name: Option<&str>) -> Section {
+        Section {
+            kind: kind.to_string(),
+            name: name.map(|name| name.to_string()),
+            options: vec![],
+        }
+    }
+
+    /// Returns a single option's value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.iter().find(|&&(ref k, _)| k == key).and_then(|&(_, ref value)| match *value {
+            Value::Single(ref value) => Some(&value[..]),
+            Value::List(_) => None,
+        })
+    }
+
+    /// Sets a single option, replacing any previous value.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let value = Value::Single(value.to_string());
+        match self.options.iter_mut().find(|&&mut (ref k, _)| k == key) {
+            Some(option) => option.1 = value,
+            None => self.options.push((key.to_string(), value)),
+        }
+    }
+
+    /// Removes an option.
+    pub fn unset(&mut self, key: &str) {
+        self.options.retain(|&(ref k, _)| k != key);
+    }
+}
+
+/// A parsed UCI file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Config {
+    pub sections: Vec<Section>,
+}
+
+impl Config {
+    /// Parses the contents of a UCI file. Comments are not kept.
+    pub fn parse(text: &str) -> io::Result<Config> {
+        let mut config = Config::default();
+        for (number, line) in text.lines().enumerate() {
+            let words = try!(split_words(line).map_err(|e| {
+                invalid_data(&format!("line {}: {}", number + 1, e))
+            }));
+            let malformed = || invalid_data(&format!("line {}: expected a section or option", number + 1));
+            match words.first().map(|word| &word[..]) {
+                None => {}
+                Some("config") if words.len() == 2 || words.len() == 3 => {
+                    config.sections.push(Section::new(&words[1], words.get(2).map(|name| &name[..])));
+                }
+                Some("option") if words.len() == 3 => {
+                    let section = try!(config.sections.last_mut().ok_or_else(&malformed));
+                    section.set(&words[1], &words[2]);
+                }
+                Some("list") if words.len() == 3 => {
+                    let section = try!(config.sections.last_mut().ok_or_else(&malformed));
+                    let position = section.options.iter().position(|&(ref k, _)| *k == words[1]);
+                    match position.map(|i| &mut section.options[i].1) {
+                        Some(&mut Value::List(ref mut values)) => values.push(words[2].clone()),
+                        _ => {
+                            section.unset(&words[1]);
+                            section.options.push((words[1].clone(), Value::List(vec![words[2].clone()])));
+                        }
+                    }
+                }
+                // "package" lines only matter for multi-package exports.
+                Some("package") => {}
+                Some(_) => return Err(malformed()),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Sections of the given kind.
+    pub fn sections<'a>(&'a self, kind: &'a str) -> Box<Iterator<Item = &'a Section> + 'a> {
+        Box::new(self.sections.iter().filter(move |section| section.kind == kind))
+    }
+}
+
+impl fmt::Display for Config {
+    /// Formats the file the way `uci commit` writes it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for section in &self.sections {
+            try!(write!(f, "\nconfig {}", section.kind));
+            if let Some(ref name) = section.name {
+                try!(write!(f, " {}", quote(name)));
+            }
+            try!(writeln!(f));
+            for &(ref key, ref value) in &section.options {
+                match *value {
+                    Value::Single(ref value) => try!(writeln!(f, "\toption {} {}", key, quote(value))),
+                    Value::List(ref values) => {
+                        for value in values {
+                            try!(writeln!(f, "\tlist {} {}", key, quote(value)));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// WiFi security, as set in a UCI `encryption` option.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Security {
+    None,
+    Wep,
+    Psk,
+    Psk2,
+}
+
+impl Security {
+    fn as_uci(&self) -> &'static str {
+        match *self {
+            Security::None => "none",
+            Security::Wep => "wep",
+            Security::Psk => "psk",
+            Security::Psk2 => "psk2",
+        }
+    }
+
+    fn from_uci(encryption: &str) -> Option<Security> {
+        // Ciphers can be appended, as in "psk2+ccmp".
+        match encryption.split('+').next().unwrap_or("") {
+            "none" | "" => Some(Security::None),
+            "wep" | "wep-open" | "wep-shared" => Some(Security::Wep),
+            "psk" => Some(Security::Psk),
+            "psk2" | "psk-mixed" => Some(Security::Psk2),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a WiFi interface joins a network or hosts one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Station,
+    AccessPoint,
+    Other,
+}
+
+/// A configured WiFi interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub mode: Mode,
+    /// `None` for encryption types this module does not know.
+    pub security: Option<Security>,
+    pub enabled: bool,
+    /// The logical network, from `/etc/config/network`, the interface joins.
+    pub network: Option<String>,
+}
+
+/// A logical network interface from `/etc/config/network`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    /// How the interface gets its address, such as "dhcp" or "static".
+    pub proto: Option<String>,
+    pub ipaddr: Option<String>,
+    pub netmask: Option<String>,
+}
+
+/// Reads and writes the Tessel's network configuration.
+pub struct Network {
+    root: PathBuf,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::with_root("/")
+    }
+
+    /// Uses the configuration files under `root` instead of `/`.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Network {
+        Network {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Lists the configured WiFi interfaces.
+    pub fn networks(&self) -> io::Result<Vec<WifiNetwork>> {
+        let wireless = try!(self.load(WIRELESS_PATH));
+        Ok(wireless.sections("wifi-iface").map(|iface| WifiNetwork {
+            ssid: iface.get("ssid").unwrap_or("").to_string(),
+            mode: match iface.get("mode") {
+                Some("sta") => Mode::Station,
+                Some("ap") => Mode::AccessPoint,
+                _ => Mode::Other,
+            },
+            security: Security::from_uci(iface.get("encryption").unwrap_or("none")),
+            enabled: iface.get("disabled") != Some("1"),
+            network: iface.get("network").map(|network| network.to_string()),
+        }).collect())
+    }
+
+    /// Lists the logical interfaces in `/etc/config/network`.
+    pub fn interfaces(&self) -> io::Result<Vec<Interface>> {
+        let network = try!(self.load(NETWORK_PATH));
+        Ok(network.sections("interface").map(|section| Interface {
+            name: section.name.clone().unwrap_or_default(),
+            proto: section.get("proto").map(|proto| proto.to_string()),
+            ipaddr: section.get("ipaddr").map(|ipaddr| ipaddr.to_string()),
+            netmask: section.get("netmask").map(|netmask| netmask.to_string()),
+        }).collect())
+    }
+
+    /// Joins the network `ssid`, replacing the current station settings.
+    pub fn set_station(&self, ssid: &str, password: Option<&str>, security: Security) -> io::Result<()> {
+        try!(check_password(password, security));
+        let mut wireless = try!(self.load(WIRELESS_PATH));
+        let iface = try!(wifi_iface(&mut wireless, "sta", "wan"));
+        iface.set("ssid", ssid);
+        iface.set("encryption", security.as_uci());
+        match password {
+            Some(password) if security != Security::None => iface.set("key", password),
+            _ => iface.unset("key"),
+        }
+        iface.set("disabled", "0");
+        self.save(WIRELESS_PATH, &wireless)
+    }
+
+    /// Turns the Tessel's own access point on or off.
+    pub fn set_access_point_enabled(&self, enabled: bool) -> io::Result<()> {
+        let mut wireless = try!(self.load(WIRELESS_PATH));
+        {
+            let iface = match wireless.sections.iter_mut()
+                .find(|s| s.kind == "wifi-iface" && s.get("mode") == Some("ap")) {
+                Some(iface) => iface,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "No access point is configured.")),
+            };
+            iface.set("disabled", if enabled { "0" } else { "1" });
+        }
+        self.save(WIRELESS_PATH, &wireless)
+    }
+
+    fn load(&self, path: &str) -> io::Result<Config> {
+        let mut text = String::new();
+        try!(try!(File::open(self.root.join(path))).read_to_string(&mut text));
+        Config::parse(&text)
+    }
+
+    // Replaces the file through a rename, so a power cut can't leave it
+    // half written. The new file keeps the old one's permissions, since the
+    // wireless configuration holds keys.
+    fn save(&self, path: &str, config: &Config) -> io::Result<()> {
+        let path = self.root.join(path);
+        let temp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&temp));
+            if let Ok(metadata) = fs::metadata(&path) {
+                try!(file.set_permissions(metadata.permissions()));
+            }
+            try!(file.write_all(config.to_string().as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(temp, path)
+    }
+}
+
+// Finds the first WiFi interface in `mode`, adding one on the first radio
+// if there is none.
+fn wifi_iface<'a>(wireless: &'a mut Config, mode: &str, network: &str) -> io::Result<&'a mut Section> {
+    let position = wireless.sections.iter().position(|s| s.kind == "wifi-iface" && s.get("mode") == Some(mode));
+    let index = match position {
+        Some(index) => index,
+        None => {
+            let device = match wireless.sections("wifi-device").next().and_then(|s| s.name.clone()) {
+                Some(device) => device,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "No WiFi radio is configured.")),
+            };
+            let mut iface = Section::new("wifi-iface", None);
+            iface.set("device", &device);
+            iface.set("network", network);
+            iface.set("mode", mode);
+            wireless.sections.push(iface);
+            wireless.sections.len() - 1
+        }
+    };
+    Ok(&mut wireless.sections[index])
+}
+
+fn check_password(password: Option<&str>, security: Security) -> io::Result<()> {
+    let is_hex = |key: &str| key.chars().all(|c| c.is_ascii_hexdigit());
+    let valid = match (security, password) {
+        (Security::None, _) => true,
+        // 5 or 13 ASCII characters, or 10 or 26 hex digits.
+        (Security::Wep, Some(key)) => match key.len() {
+            5 | 13 => true,
+            10 | 26 => is_hex(key),
+            _ => false,
+        },
+        // A passphrase of 8 to 63 characters, or a 64 hex digit key.
+        (Security::Psk, Some(key)) | (Security::Psk2, Some(key)) => match key.len() {
+            64 => is_hex(key),
+            8...63 => true,
+            _ => false,
+        },
+        (_, None) => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid password for the WiFi security type."))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Splits a UCI line into words, handling quotes and comments.
+fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.peek() {
+            None | Some(&'#') => return Ok(words),
+            _ => {}
+        }
+
+        // Adjacent quoted and bare parts form one word, as in a shell.
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            match c {
+                '\'' | '"' => {
+                    loop {
+                        match chars.next() {
+                            Some(q) if q == c => break,
+                            Some('\\') if c == '"' => word.extend(chars.next()),
+                            Some(other) => word.push(other),
+                            None => return Err("unterminated quote"),
+                        }
+                    }
+                }
+                '\\' => word.extend(chars.next()),
+                _ => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+}
+
+// Single-quotes a value, writing embedded quotes as '\''.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+
+    const WIRELESS: &'static str = "
+config wifi-device 'radio0'
+\toption type 'mac80211'
+\toption channel '11'
+
+config wifi-iface
+\toption device 'radio0'
+\toption network 'lan'
+\toption mode 'ap'
+\toption ssid 'Tessel-02A3B4C5D6E7'
+\toption encryption 'none'
+
+# Added by the Tessel CLI.
+config wifi-iface
+\toption device 'radio0'
+\toption network 'wan'
+\toption mode 'sta'
+\toption ssid \"Bob's WiFi\"
+\toption encryption psk2
+\toption key 'hunter22'
+\toption disabled '1'
+";
+
+    const NETWORK: &'static str = "
+config interface 'loopback'
+\toption ifname 'lo'
+\toption proto 'static'
+\toption ipaddr '127.0.0.1'
+\toption netmask '255.0.0.0'
+
+config interface 'wan'
+\toption proto 'dhcp'
+\tlist dns '8.8.8.8'
+\tlist dns '8.8.4.4'
+";
+
+    #[test]
+    fn uci_round_trip() {
+        let config = Config::parse(NETWORK).unwrap();
+        assert_eq!(Some(&Value::List(vec!["8.8.8.8".to_string(), "8.8.4.4".to_string()])),
+                   config.sections[1].options.iter().find(|o| o.0 == "dns").map(|o| &o.1));
+        assert_eq!(NETWORK, config.to_string());
+
+        let mut section = Section::new("wifi-iface", None);
+        section.set("ssid", "it's");
+        let config = Config { sections: vec![section] };
+        assert_eq!(config, Config::parse(&config.to_string()).unwrap());
+        assert!(Config::parse("option ssid 'orphan'").is_err());
+    }
+
+    #[test]
+    fn configures_wifi() {
+        let fake = tempfile::tempdir().unwrap();
+        let root = fake.path();
+        fs::create_dir_all(root.join("etc/config")).unwrap();
+        File::create(root.join(WIRELESS_PATH)).unwrap().write_all(WIRELESS.as_bytes()).unwrap();
+        File::create(root.join(NETWORK_PATH)).unwrap().write_all(NETWORK.as_bytes()).unwrap();
+        fs::set_permissions(root.join(WIRELESS_PATH), fs::Permissions::from_mode(0o600)).unwrap();
+        let network = Network::with_root(&root);
+
+        let networks = network.networks().unwrap();
+        assert_eq!(2, networks.len());
+        assert_eq!(WifiNetwork {
+            ssid: "Bob's WiFi".to_string(),
+            mode: Mode::Station,
+            security: Some(Security::Psk2),
+            enabled: false,
+            network: Some("wan".to_string()),
+        }, networks[1]);
+        assert_eq!(vec!["loopback", "wan"],
+                   network.interfaces().unwrap().iter().map(|i| &i.name[..]).collect::<Vec<_>>());
+
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   network.set_station("Cafe", Some("short"), Security::Psk2).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   network.set_station("Cafe", Some(&"z".repeat(64)), Security::Psk2).unwrap_err().kind());
+        // Ten bytes, but not ten hex digits.
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   network.set_station("Cafe", Some("\u{661}\u{662}\u{663}\u{664}\u{665}"), Security::Wep)
+                       .unwrap_err().kind());
+        network.set_station("Cafe", None, Security::None).unwrap();
+        network.set_access_point_enabled(false).unwrap();
+
+        let networks = network.networks().unwrap();
+        assert!(!networks[0].enabled);
+        assert_eq!(("Cafe", Some(Security::None), true),
+                   (&networks[1].ssid[..], networks[1].security, networks[1].enabled));
+        let wireless = network.load(WIRELESS_PATH).unwrap();
+        assert_eq!(None, wireless.sections[2].get("key"));
+        let mode = fs::metadata(root.join(WIRELESS_PATH)).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+}
@@ -0,0 +1,91 @@
+//! Power switches for the module ports and the USB port.
+//!
+//! Each switch is a GPIO exported to sysfs, driving the load switch in
+//! front of its port. The stock Tessel 2 image does not export these GPIOs,
+//! so there are no default paths: set `TESSEL_PORT_A_POWER`,
+//! `TESSEL_PORT_B_POWER` or `TESSEL_USB_POWER` to the value file your
+//! board's image exports. Without them the switches are reported missing.
+//! Paths are relative to a root directory so the switches can be exercised
+//! against fake files.
+//! # Example
+//! ```rust,no_run
+//! use std::thread;
+//! use std::time::Duration;
+//! use tessel::Tessel;
+//!
+//! // Power-cycle a wedged sensor.
+//! let (port_a, _) = Tessel::ports().unwrap();
+//! port_a.power(false).unwrap();
+//! thread::sleep(Duration::from_millis(500));
+//! port_a.power(true).unwrap();
+//! ```
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the GPIO value file switching module port A.
+pub const PORT_A_POWER_VAR: &'static str = "TESSEL_PORT_A_POWER";
+/// Environment variable naming the GPIO value file switching module port B.
+pub const PORT_B_POWER_VAR: &'static str = "TESSEL_PORT_B_POWER";
+/// Environment variable naming the GPIO value file switching the USB port.
+pub const USB_POWER_VAR: &'static str = "TESSEL_USB_POWER";
+
+/// A GPIO that turns power to a port on or off.
+#[derive(Debug, Clone)]
+pub struct PowerSwitch {
+    path: PathBuf,
+}
+
+impl PowerSwitch {
+    /// Creates a switch driven by an arbitrary GPIO value file.
+    pub fn new<P: AsRef<Path>>(path: P) -> PowerSwitch {
+        PowerSwitch {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The switch for module port A under `root`. Fails with `NotFound`
+    /// unless `TESSEL_PORT_A_POWER` is set.
+    pub fn port_a<P: AsRef<Path>>(root: P) -> io::Result<PowerSwitch> {
+        PowerSwitch::configured(root.as_ref(), PORT_A_POWER_VAR)
+    }
+
+    /// The switch for module port B under `root`. Fails with `NotFound`
+    /// unless `TESSEL_PORT_B_POWER` is set.
+    pub fn port_b<P: AsRef<Path>>(root: P) -> io::Result<PowerSwitch> {
+        PowerSwitch::configured(root.as_ref(), PORT_B_POWER_VAR)
+    }
+
+    /// The switch for the USB port under `root`. Fails with `NotFound`
+    /// unless `TESSEL_USB_POWER` is set.
+    pub fn usb<P: AsRef<Path>>(root: P) -> io::Result<PowerSwitch> {
+        PowerSwitch::configured(root.as_ref(), USB_POWER_VAR)
+    }
+
+    // Uses the path in the environment variable `var`.
+    fn configured(root: &Path, var: &str) -> io::Result<PowerSwitch> {
+        match env::var(var) {
+            Ok(path) => Ok(PowerSwitch::new(root.join(path.trim_start_matches('/')))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "No power switch configured; set {} to its GPIO value file.", var))),
+        }
+    }
+
+    pub fn set(&self, on: bool) -> io::Result<()> {
+        let mut file = try!(OpenOptions::new().write(true).truncate(true).open(&self.path));
+        file.write_all(if on { b"1" } else { b"0" })
+    }
+
+    pub fn is_on(&self) -> io::Result<bool> {
+        let mut value = String::new();
+        try!(try!(File::open(&self.path)).read_to_string(&mut value));
+        match value.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected power GPIO value.")),
+        }
+    }
+}
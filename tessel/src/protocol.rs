@@ -1,5 +1,14 @@
 use std::io;
 use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::cmp;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use stats::Stats;
+use tracing::span::EnteredSpan;
+use transport::Transport;
 use unix_socket::UnixStream;
 
 use self::Command::*;
@@ -33,6 +42,8 @@ mod raw_cmd {
     pub const GPIO_PULL: u8 = 0x1A;
     pub const PWM_DUTY_CYCLE: u8 = 0x1B;
     pub const PWM_PERIOD: u8 = 0x1C;
+    // Protocol revision 1 extension; the stock firmware doesn't define it.
+    pub const VERSION: u8 = 0x1D;
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -67,12 +78,111 @@ pub enum Command<'a> {
     PwmDutyCycle{ pin: u8, duty_cycle: u16 },
     PwmPeriod{ prescalar: u8, tcc_id: u8, period: u16 },
 
+    /// Asks for the firmware's protocol revision, version and serial
+    /// number. Only firmware speaking protocol revision 1 defines it, so
+    /// don't send it to the stock firmware.
+    Version,
+
     Rx(u8),
     Echo(&'a [u8]),
     Tx(&'a [u8]),
     TxRx(&'a [u8]),
 }
 
+impl<'a> Command<'a> {
+    /// The command's name, as used in `Stats::commands`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Nop => "Nop",
+            Flush => "Flush",
+            GpioIn(_) => "GpioIn",
+            GpioHigh(_) => "GpioHigh",
+            GpioLow(_) => "GpioLow",
+            GpioToggle(_) => "GpioToggle",
+            GpioWait(_) => "GpioWait",
+            GpioInt(_) => "GpioInt",
+            GpioCfg(_) => "GpioCfg",
+            GpioInput(_) => "GpioInput",
+            GpioRawRead(_) => "GpioRawRead",
+            GpioPull(_) => "GpioPull",
+            AnalogRead(_) => "AnalogRead",
+            AnalogWrite{ .. } => "AnalogWrite",
+            EnableSpi{ .. } => "EnableSpi",
+            DisableSpi => "DisableSpi",
+            EnableI2c{ .. } => "EnableI2c",
+            DisableI2c => "DisableI2c",
+            EnableUart{ .. } => "EnableUart",
+            DisableUart => "DisableUart",
+            Start(_) => "Start",
+            Stop => "Stop",
+            PwmDutyCycle{ .. } => "PwmDutyCycle",
+            PwmPeriod{ .. } => "PwmPeriod",
+            Version => "Version",
+            Rx(_) => "Rx",
+            Echo(_) => "Echo",
+            Tx(_) => "Tx",
+            TxRx(_) => "TxRx",
+        }
+    }
+
+    // Whether the coprocessor answers the command straight away.
+    fn expects_reply(&self) -> bool {
+        match *self {
+            GpioIn(_) | GpioRawRead(_) | AnalogRead(_) | Version | Rx(_) | Echo(_) | TxRx(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Largest number of bytes a single command can transfer. Longer `Tx`, `TxRx`
+/// and `Echo` commands are split into several commands of at most this size,
+/// each of which gets its own reply; longer reads need several `Rx` commands.
+pub const MAX_TRANSFER: usize = 255;
+
+// DATA, protocol revision, firmware version and 128-bit serial number.
+const VERSION_REPLY_LEN: usize = 21;
+
+/// Lengths of the replies the coprocessor sends to a stream of encoded
+/// commands, in order, counting each reply's status byte. A reply starting
+/// with `NACK` ends there. Lets the port broker tell replies apart from
+/// asynchronous events.
+pub fn reply_lengths(commands: &[u8]) -> io::Result<Vec<usize>> {
+    let mut replies = vec![];
+    let mut rest = commands;
+    while let Some(&cmd) = rest.first() {
+        // Payload length, for the commands that carry one.
+        let n = rest.get(1).map(|&n| n as usize);
+        let (len, reply) = match (cmd, n) {
+            (raw_cmd::NOP, _) | (raw_cmd::FLUSH, _) | (raw_cmd::DISABLE_SPI, _) |
+            (raw_cmd::DISABLE_I2C, _) | (raw_cmd::DISABLE_UART, _) | (raw_cmd::STOP, _) => (1, 0),
+            (raw_cmd::VERSION, _) => (1, VERSION_REPLY_LEN),
+            (raw_cmd::GPIO_IN, _) | (raw_cmd::GPIO_RAW_READ, _) => (2, 1),
+            (raw_cmd::ANALOG_READ, _) => (2, 3),
+            (raw_cmd::GPIO_HIGH, _) | (raw_cmd::GPIO_LOW, _) | (raw_cmd::GPIO_TOGGLE, _) |
+            (raw_cmd::GPIO_WAIT, _) | (raw_cmd::GPIO_INT, _) | (raw_cmd::GPIO_CFG, _) |
+            (raw_cmd::GPIO_INPUT, _) | (raw_cmd::GPIO_PULL, _) | (raw_cmd::ENABLE_I2C, _) |
+            (raw_cmd::START, _) => (2, 0),
+            (raw_cmd::ANALOG_WRITE, _) | (raw_cmd::ENABLE_UART, _) => (3, 0),
+            (raw_cmd::ENABLE_SPI, _) | (raw_cmd::PWM_DUTY_CYCLE, _) | (raw_cmd::PWM_PERIOD, _) => (4, 0),
+            (raw_cmd::RX, Some(n)) => (2, 1 + n),
+            (raw_cmd::TX, Some(n)) => (2 + n, 0),
+            (raw_cmd::ECHO, Some(n)) | (raw_cmd::TXRX, Some(n)) => (2 + n, 1 + n),
+            (raw_cmd::RX, None) | (raw_cmd::TX, None) | (raw_cmd::ECHO, None) | (raw_cmd::TXRX, None) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated command."));
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown command.")),
+        };
+        if rest.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated command."));
+        }
+        if reply > 0 {
+            replies.push(reply);
+        }
+        rest = &rest[len..];
+    }
+    Ok(replies)
+}
+
 /// Starting byte of reply packets. Because this is extensible, we use
 /// a list of constants instead of an enum.
 pub mod reply {
@@ -90,50 +200,196 @@ pub mod reply {
     pub const ASYNC_UART_RX: Reply = Reply(0xD0);
 }
 
+// Delays between attempts to reconnect a transport that has gone away.
+const RECONNECT_INITIAL_DELAY_MS: u64 = 50;
+const RECONNECT_MAX_DELAY_MS: u64 = 2000;
+const RECONNECT_MAX_ATTEMPTS: u32 = 10;
+// Longest a reconnection may keep the port locked; later operations retry.
+const RECONNECT_TIMEOUT_MS: u64 = 1000;
+// How long a poisoned socket that cannot reconnect waits for stray replies.
+const DRAIN_TIMEOUT_MS: u64 = 50;
+
+/// Opens a fresh transport to the same coprocessor port.
+pub type Connector = Box<Fn() -> io::Result<Box<Transport>> + Send>;
+
+/// Sent to `PortSocket::reconnections` listeners after the transport was
+/// re-established and the port's configuration replayed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reconnected {
+    /// How many connection attempts it took.
+    pub attempts: u32,
+}
+
 /// Socket that communicates with the SAMD21.
 pub struct PortSocket {
-    _socket_path: String,
-    socket: UnixStream,
+    socket: Box<Transport>,
+    // Used to replace `socket` if it disconnects, e.g. when spid restarts.
+    connector: Option<Connector>,
+    // Commands that configure the port, keyed by command and pin, to replay
+    // after reconnecting.
+    config: Vec<((u8, u8), Vec<u8>)>,
+    listeners: Vec<Sender<Reconnected>>,
+    in_transaction: bool,
+    // How long to wait for a reply before giving up, for the whole port and
+    // for the transaction in progress.
+    timeout: Option<Duration>,
+    call_timeout: Option<Duration>,
+    stats: Stats,
+    // When the oldest command still waiting for its reply was sent.
+    awaiting_since: Option<Instant>,
+    // Whether replies may be in flight that nobody will read.
+    poisoned: bool,
 }
 
 impl PortSocket {
     pub fn new(path: &str) -> PortSocket {
+        let path = path.to_string();
         // Connect to the unix domain socket for this port
-        let socket = UnixStream::connect(path).unwrap();
+        PortSocket::with_connector(Box::new(move || {
+            UnixStream::connect(&path).map(|socket| Box::new(socket) as Box<Transport>)
+        })).unwrap()
+    }
 
+    /// Creates a socket that speaks the port protocol over any transport.
+    pub fn with_transport(transport: Box<Transport>) -> PortSocket {
         PortSocket {
-            _socket_path: path.to_string(),
-            socket: socket
+            socket: transport,
+            connector: None,
+            config: vec![],
+            listeners: vec![],
+            in_transaction: false,
+            timeout: None,
+            call_timeout: None,
+            stats: Stats::default(),
+            awaiting_since: None,
+            poisoned: false,
         }
     }
 
+    /// Creates a socket using transports opened by `connector`, which is
+    /// called again to reconnect if the transport disconnects.
+    pub fn with_connector(connector: Connector) -> io::Result<PortSocket> {
+        let transport = try!(connector());
+        let mut socket = PortSocket::with_transport(transport);
+        socket.connector = Some(connector);
+        Ok(socket)
+    }
+
+    /// Returns a channel that receives an event each time the socket
+    /// reconnects. Any transaction in progress at that moment fails with
+    /// `ConnectionReset`, and the coprocessor may have reset pin states that
+    /// are not part of the replayed configuration.
+    pub fn reconnections(&mut self) -> Receiver<Reconnected> {
+        let (tx, rx) = mpsc::channel();
+        self.listeners.push(tx);
+        rx
+    }
+
+    /// Marks the socket as out of step with the coprocessor, which may still
+    /// send replies that nobody will read. The next transaction starts on a
+    /// fresh connection, or after draining the socket if it cannot reconnect.
+    pub fn poison(&mut self) {
+        self.poisoned = true;
+    }
+
+    /// Marks the start of a transaction on the transport.
+    pub fn begin(&mut self) -> io::Result<()> {
+        if self.poisoned {
+            try!(self.resync());
+        }
+        match self.socket.begin_transaction() {
+            Err(ref e) if is_disconnect(e) && self.can_reconnect() => {
+                self.in_transaction = true;
+                // `reconnect` begins the transaction on the new transport.
+                let result = self.reconnect();
+                self.in_transaction = result.is_ok();
+                result
+            }
+            result => {
+                self.in_transaction = result.is_ok();
+                result
+            }
+        }
+    }
+
+    /// Marks the end of a transaction on the transport.
+    pub fn end(&mut self) -> io::Result<()> {
+        self.in_transaction = false;
+        self.call_timeout = None;
+        self.socket.end_transaction()
+    }
+
+    /// Sets how long to wait for each reply from the coprocessor. `None`,
+    /// the default, waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
     pub fn raw_write(&mut self, buffer: &[u8]) -> io::Result<()> {
-        self.socket.write_all(buffer)
+        try!(match self.socket.write_all(buffer) {
+            Err(ref e) if is_disconnect(e) && self.can_reconnect() => {
+                try!(self.reconnect());
+                if self.in_transaction {
+                    // The new connection never saw the transaction's earlier
+                    // commands, so the rest of it would be out of context.
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                              "Port socket was reset during the transaction."));
+                }
+                self.socket.write_all(buffer)
+            }
+            result => result,
+        });
+        self.stats.bytes_out += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Sends the port's I2C, SPI, UART, pull, interrupt and PWM
+    /// configuration again, as after a reconnection.
+    pub fn restore(&mut self) -> io::Result<()> {
+        let config = self.config.clone();
+        for (_, bytes) in config {
+            try!(self.raw_write(&bytes));
+        }
+        Ok(())
     }
 
     pub fn write_command(&mut self, cmd: Command) -> io::Result<()> {
-        let socket = &mut self.socket;
+        let mut buffer = vec![];
+        try!(PortSocket::encode(&mut buffer, cmd));
+        self.remember(cmd, &buffer);
+        try!(self.raw_write(&buffer));
+        trace!(command = cmd.name(), bytes = ?buffer, "sent command");
+
+        *self.stats.commands.entry(cmd.name()).or_insert(0) += 1;
+        if cmd.expects_reply() && self.awaiting_since.is_none() {
+            self.awaiting_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of the socket's counters.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Counts a NACK seen in a reply.
+    pub fn record_nack(&mut self) {
+        self.stats.nacks += 1;
+    }
+
+    fn encode(socket: &mut Vec<u8>, cmd: Command) -> io::Result<()> {
         match cmd {
             Nop => socket.write_all(&[raw_cmd::NOP]),
             Flush => socket.write_all(&[raw_cmd::FLUSH]),
+            Version => socket.write_all(&[raw_cmd::VERSION]),
             Rx(len) => socket.write_all(&[raw_cmd::RX, len]),
-            Echo(data) => {
-                assert!(data.len() <= u8::max_value() as usize);
-                try!(socket.write_all(&[raw_cmd::ECHO, data.len() as u8]));
-                socket.write_all(data)
-            },
-            Tx(data) => {
-                for slice in data.chunks(u8::max_value() as usize) {
-                    try!(socket.write_all(&[raw_cmd::TX, slice.len() as u8]));
-                    try!(socket.write_all(slice));
-                }
-                Ok(())
-            }
-            TxRx(data) => {
-                assert!(data.len() <= u8::max_value() as usize);
-                try!(socket.write_all(&[raw_cmd::TXRX, data.len() as u8]));
-                socket.write_all(data)
-            }
+            Echo(data) => write_chunked(socket, raw_cmd::ECHO, data),
+            Tx(data) => write_chunked(socket, raw_cmd::TX, data),
+            TxRx(data) => write_chunked(socket, raw_cmd::TXRX, data),
             GpioIn(pin) => socket.write_all(&[raw_cmd::GPIO_IN, pin]),
             GpioHigh(pin) => socket.write_all(&[raw_cmd::GPIO_HIGH, pin]),
             GpioLow(pin) => socket.write_all(&[raw_cmd::GPIO_LOW, pin]),
@@ -163,7 +419,263 @@ impl PortSocket {
         }
     }
 
+    // Records commands that configure the port, replacing any earlier
+    // setting of the same thing.
+    fn remember(&mut self, cmd: Command, bytes: &[u8]) {
+        let key = match cmd {
+            EnableI2c{ .. } | DisableI2c => (raw_cmd::ENABLE_I2C, 0),
+            EnableSpi{ .. } | DisableSpi => (raw_cmd::ENABLE_SPI, 0),
+            EnableUart{ .. } | DisableUart => (raw_cmd::ENABLE_UART, 0),
+            // The upper bits of the pin carry the mode.
+            GpioPull(pin) => (raw_cmd::GPIO_PULL, pin & 0x0F),
+            GpioInt(pin) => (raw_cmd::GPIO_INT, pin & 0x0F),
+            PwmPeriod{ tcc_id, .. } => (raw_cmd::PWM_PERIOD, tcc_id),
+            PwmDutyCycle{ pin, .. } => (raw_cmd::PWM_DUTY_CYCLE, pin),
+            _ => return,
+        };
+
+        let position = self.config.iter().position(|&(k, _)| k == key);
+        match cmd {
+            DisableI2c | DisableSpi | DisableUart => {
+                if let Some(i) = position {
+                    self.config.remove(i);
+                }
+            }
+            _ => match position {
+                Some(i) => self.config[i].1 = bytes.to_vec(),
+                None => self.config.push((key, bytes.to_vec())),
+            },
+        }
+    }
+
+    fn can_reconnect(&self) -> bool {
+        self.connector.is_some()
+    }
+
+    // Replaces the transport, backing off between attempts, then restores
+    // the port's configuration and lets listeners know. Gives up once the
+    // next attempt would keep the port locked past `RECONNECT_TIMEOUT_MS`.
+    fn reconnect(&mut self) -> io::Result<()> {
+        let deadline = Instant::now() + Duration::from_millis(RECONNECT_TIMEOUT_MS);
+        let mut delay = RECONNECT_INITIAL_DELAY_MS;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match (self.connector.as_ref().unwrap())() {
+                Ok(transport) => {
+                    self.socket = transport;
+                    break;
+                }
+                Err(e) => {
+                    let retry_at = Instant::now() + Duration::from_millis(delay);
+                    if attempts >= RECONNECT_MAX_ATTEMPTS || retry_at > deadline {
+                        error!(attempts, error = %e, "could not reconnect to the coprocessor");
+                        return Err(e);
+                    }
+                    debug!(attempts, error = %e, delay_ms = delay, "reconnect failed, retrying");
+                    thread::sleep(Duration::from_millis(delay));
+                    delay = cmp::min(delay * 2, RECONNECT_MAX_DELAY_MS);
+                }
+            }
+        }
+
+        if self.in_transaction {
+            try!(self.socket.begin_transaction());
+        }
+        for &(_, ref bytes) in &self.config {
+            try!(self.socket.write_all(bytes));
+        }
+
+        info!(attempts, "reconnected to the coprocessor");
+        self.stats.reconnections += 1;
+        // Replies to anything sent before are lost.
+        self.awaiting_since = None;
+        let event = Reconnected {
+            attempts: attempts,
+        };
+        self.listeners.retain(|listener| listener.send(event).is_ok());
+        Ok(())
+    }
+
+    // Gets back in step after `poison`, dropping any late replies.
+    fn resync(&mut self) -> io::Result<()> {
+        self.poisoned = false;
+        if self.can_reconnect() {
+            debug!("reconnecting to discard stray replies");
+            return self.reconnect();
+        }
+
+        if self.socket.set_read_timeout(Some(Duration::from_millis(DRAIN_TIMEOUT_MS))).is_err() {
+            warn!("cannot drain stray replies from a transport without read timeouts");
+            return Ok(());
+        }
+        let mut buf = [0; 256];
+        loop {
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(len) => debug!(bytes = ?&buf[..len], "discarded stray reply"),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fills `buffer` with reply bytes, failing with `TimedOut` if they do not
+    /// all arrive within the timeout.
     pub fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
-        self.socket.read_exact(buffer)
+        let timeout = self.call_timeout.or(self.timeout);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        if deadline.is_none() {
+            try!(self.socket.set_read_timeout(None));
+        }
+
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(self.timed_out());
+                }
+                try!(self.socket.set_read_timeout(Some(deadline - now)));
+            }
+
+            match self.socket.read(&mut buffer[filled..]) {
+                Ok(0) => return self.disconnected(),
+                Err(ref e) if is_disconnect(e) => return self.disconnected(),
+                Ok(len) => filled += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Err(self.timed_out()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        trace!(bytes = ?buffer, "received reply");
+        self.stats.bytes_in += buffer.len() as u64;
+        if let Some(sent) = self.awaiting_since.take() {
+            self.stats.latency.record(sent.elapsed());
+        }
+        Ok(())
+    }
+
+    fn timed_out(&mut self) -> io::Error {
+        warn!("timed out waiting for the coprocessor");
+        self.stats.timeouts += 1;
+        // A late reply must not be counted, or read, as another command's.
+        self.awaiting_since = None;
+        self.poison();
+        io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the coprocessor.")
+    }
+
+    // Handles the transport closing while waiting for a reply. The reply is
+    // lost, but reconnecting now lets the next operation succeed.
+    fn disconnected(&mut self) -> io::Result<()> {
+        warn!("port socket closed while waiting for a reply");
+        if !self.can_reconnect() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Port socket closed."));
+        }
+        try!(self.reconnect());
+        Err(io::Error::new(io::ErrorKind::ConnectionReset, "Port socket was reset before replying."))
+    }
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected => true,
+        _ => false,
+    }
+}
+
+// Writes a command carrying a payload, splitting it into as many commands as
+// its length requires.
+fn write_chunked<W: Write>(socket: &mut W, cmd: u8, data: &[u8]) -> io::Result<()> {
+    for slice in data.chunks(MAX_TRANSFER) {
+        try!(socket.write_all(&[cmd, slice.len() as u8]));
+        try!(socket.write_all(slice));
+    }
+    Ok(())
+}
+
+/// Exclusive use of a `PortSocket` for one transaction.
+///
+/// Holds the socket lock and tells the transport where the transaction
+/// starts and ends, so that a shared transport (such as a port broker) can
+/// keep it atomic with respect to other processes.
+pub struct Transaction<'a> {
+    socket: MutexGuard<'a, PortSocket>,
+    // Groups the transaction's commands and replies in traces.
+    _span: EnteredSpan,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(socket: &'a Mutex<PortSocket>) -> io::Result<Transaction<'a>> {
+        Transaction::begin_with_timeout(socket, None)
+    }
+
+    /// Begins a transaction whose replies use `timeout` instead of the
+    /// port's timeout, if it is set.
+    pub fn begin_with_timeout(socket: &'a Mutex<PortSocket>, timeout: Option<Duration>) -> io::Result<Transaction<'a>> {
+        let span = debug_span!("transaction").entered();
+        let mut guard = socket.lock().unwrap();
+        try!(guard.begin());
+        guard.call_timeout = timeout;
+        Ok(Transaction {
+            socket: guard,
+            _span: span,
+        })
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = PortSocket;
+
+    fn deref(&self) -> &PortSocket {
+        &self.socket
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    fn deref_mut(&mut self) -> &mut PortSocket {
+        &mut self.socket
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        let _ = self.socket.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport;
+
+    #[test]
+    fn long_echo_is_chunked() {
+        let (local, mut remote) = transport::pipe();
+        let mut socket = PortSocket::with_transport(Box::new(local));
+        socket.write_command(Echo(&[0x55; 300])).unwrap();
+
+        let mut sent = [0; 304];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!([raw_cmd::ECHO, 255], sent[..2]);
+        assert_eq!([raw_cmd::ECHO, 45], sent[257..259]);
+    }
+
+    #[test]
+    fn reply_lengths_follow_commands() {
+        let mut buffer = vec![];
+        for &cmd in &[Start(0x3A), Tx(&[0x0D]), Rx(6), Stop, GpioIn(2), GpioHigh(3), Echo(&[1, 2])] {
+            PortSocket::encode(&mut buffer, cmd).unwrap();
+        }
+        assert_eq!(vec![7, 1, 3], reply_lengths(&buffer).unwrap());
+        assert!(reply_lengths(&[raw_cmd::TX, 4, 0]).is_err());
+        assert!(reply_lengths(&[0xEE]).is_err());
     }
 }
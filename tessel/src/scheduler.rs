@@ -0,0 +1,251 @@
+//! Reads sensors at fixed rates on background threads.
+//!
+//! Each sensor gets its own thread, so a slow sensor on one port does not
+//! hold up a fast one on the other. Reads are scheduled on deadlines counted
+//! from when the sensor was added rather than by sleeping between reads, so
+//! the rate does not drift. A read that starts late adds to the jitter
+//! histogram; if a read overruns whole periods, those deadlines are skipped
+//! and counted as missed. Failed reads are passed on as errors, so the next
+//! read still happens on schedule.
+//! # Example
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use tessel::scheduler::Scheduler;
+//! use tessel::sensor::{Celsius, Sensor};
+//!
+//! struct Thermometer;
+//!
+//! impl Sensor for Thermometer {
+//!     type Measurement = Celsius;
+//!
+//!     fn measure(&mut self) -> std::io::Result<Celsius> {
+//!         Ok(Celsius(20.0))
+//!     }
+//! }
+//!
+//! let mut scheduler = Scheduler::new();
+//! let readings = scheduler.add("thermometer", Thermometer, Duration::from_secs(1));
+//! for reading in readings.iter().take(10) {
+//!     match reading {
+//!         Ok(reading) => println!("{:?}: {}", reading.timestamp, reading.value),
+//!         Err(e) => println!("Read failed: {}", e),
+//!     }
+//! }
+//! println!("{:?}", scheduler.stats());
+//! ```
+
+use sensor::{Reading, Sensor};
+use stats::Histogram;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Counters describing how well a sensor has kept to its schedule.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SamplingStats {
+    /// Successful reads.
+    pub samples: u64,
+    /// Reads that failed.
+    pub errors: u64,
+    /// Deadlines skipped because an earlier read overran them.
+    pub missed: u64,
+    /// How late each read started after its deadline.
+    pub jitter: Histogram,
+}
+
+struct Task {
+    name: String,
+    stats: Arc<Mutex<SamplingStats>>,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Runs sensor reads at fixed rates. Dropping the scheduler stops them.
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            tasks: vec![],
+        }
+    }
+
+    /// Reads `sensor` every `period`, sending each reading, or the error
+    /// the read failed with, to the returned channel. The sensor stops being
+    /// read once the channel is dropped.
+    pub fn add<S>(&mut self, name: &str, sensor: S, period: Duration) -> Receiver<io::Result<Reading<S::Measurement>>>
+        where S: Sensor + Send + 'static, S::Measurement: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.add_callback(name, sensor, period, move |reading| sender.send(reading).is_ok());
+        receiver
+    }
+
+    /// Reads `sensor` every `period`, passing each reading or error to
+    /// `callback` on the sensor's thread. The sensor stops being read once
+    /// `callback` returns false.
+    pub fn add_callback<S, F>(&mut self, name: &str, sensor: S, period: Duration, callback: F)
+        where S: Sensor + Send + 'static,
+              F: FnMut(io::Result<Reading<S::Measurement>>) -> bool + Send + 'static
+    {
+        let stats = Arc::new(Mutex::new(SamplingStats::default()));
+        let (stop, stopped) = mpsc::channel();
+        let thread = {
+            let name = name.to_string();
+            let stats = stats.clone();
+            thread::spawn(move || run(&name, sensor, period, callback, &stats, &stopped))
+        };
+        self.tasks.push(Task {
+            name: name.to_string(),
+            stats: stats,
+            stop: stop,
+            thread: Some(thread),
+        });
+    }
+
+    /// Each sensor's name and counters so far.
+    pub fn stats(&self) -> Vec<(String, SamplingStats)> {
+        self.tasks.iter().map(|task| (task.name.clone(), task.stats.lock().unwrap().clone())).collect()
+    }
+
+    /// Stops reading every sensor, waiting for reads in progress.
+    pub fn stop(&mut self) {
+        for task in &self.tasks {
+            let _ = task.stop.send(());
+        }
+        for task in &mut self.tasks {
+            if let Some(thread) = task.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run<S, F>(name: &str, mut sensor: S, period: Duration, mut callback: F,
+             stats: &Mutex<SamplingStats>, stopped: &Receiver<()>)
+    where S: Sensor, F: FnMut(io::Result<Reading<S::Measurement>>) -> bool
+{
+    let _span = debug_span!("sampling", sensor = name).entered();
+    let mut deadline = Instant::now();
+    loop {
+        let now = Instant::now();
+        if deadline > now {
+            match stopped.recv_timeout(deadline - now) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+        } else if stopped.try_recv().is_ok() {
+            return;
+        }
+
+        let late = Instant::now() - deadline;
+        let result = sensor.read();
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.jitter.record(late);
+            match result {
+                Ok(_) => stats.samples += 1,
+                Err(ref error) => {
+                    warn!(%error, "sensor read failed");
+                    stats.errors += 1;
+                }
+            }
+        }
+        if !callback(result) {
+            return;
+        }
+
+        // Skip any deadlines the read overran rather than reading in a burst.
+        deadline += period;
+        let now = Instant::now();
+        if now > deadline {
+            let missed = ((now - deadline).as_nanos() / period.as_nanos().max(1)) as u32 + 1;
+            debug!(missed, "sensor read overran its period");
+            stats.lock().unwrap().missed += missed as u64;
+            deadline += period * missed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor::Celsius;
+
+    struct Counter {
+        count: f64,
+        delay: Duration,
+    }
+
+    impl Sensor for Counter {
+        type Measurement = Celsius;
+
+        fn measure(&mut self) -> io::Result<Celsius> {
+            thread::sleep(self.delay);
+            self.count += 1.0;
+            Ok(Celsius(self.count))
+        }
+    }
+
+    #[test]
+    fn samples_on_deadlines() {
+        let mut scheduler = Scheduler::new();
+        let fast = scheduler.add("fast", Counter { count: 0.0, delay: Duration::from_millis(0) },
+                                 Duration::from_millis(5));
+        let slow = scheduler.add("slow", Counter { count: 0.0, delay: Duration::from_millis(25) },
+                                 Duration::from_millis(10));
+
+        let readings: Vec<_> = fast.iter().take(10).map(Result::unwrap).collect();
+        assert_eq!(Celsius(10.0), readings[9].value);
+        assert!(readings.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        slow.recv().unwrap();
+        slow.recv().unwrap();
+
+        scheduler.stop();
+        let stats = scheduler.stats();
+        assert_eq!("fast", stats[0].0);
+        assert!(stats[0].1.samples >= 10);
+        assert_eq!(stats[0].1.samples, stats[0].1.jitter.count());
+        assert!(stats[1].1.missed >= 2);
+    }
+
+    struct Broken;
+
+    impl Sensor for Broken {
+        type Measurement = Celsius;
+
+        fn measure(&mut self) -> io::Result<Celsius> {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "No reply."))
+        }
+    }
+
+    #[test]
+    fn passes_on_read_errors() {
+        let mut scheduler = Scheduler::default();
+        let readings = scheduler.add("broken", Broken, Duration::from_millis(1));
+        assert_eq!(io::ErrorKind::TimedOut, readings.recv().unwrap().unwrap_err().kind());
+        assert!(readings.recv().unwrap().is_err());
+
+        scheduler.stop();
+        let stats = &scheduler.stats()[0].1;
+        assert_eq!(0, stats.samples);
+        assert!(stats.errors >= 2);
+    }
+}
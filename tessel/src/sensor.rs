@@ -0,0 +1,217 @@
+//! A common interface to the module drivers that measure something, with
+//! the units of each measurement in its type.
+//!
+//! Every measurement can also be broken into named `Value`s with a `Unit`,
+//! so loggers and dashboards can handle readings from any sensor.
+//! # Example
+//! ```rust,no_run
+//! use std::io;
+//! use tessel::sensor::{Celsius, Fahrenheit, Measurement, Sensor};
+//!
+//! // A thermometer that always reads 20°C.
+//! struct Fixed;
+//!
+//! impl Sensor for Fixed {
+//!     type Measurement = Celsius;
+//!
+//!     fn measure(&mut self) -> io::Result<Celsius> {
+//!         Ok(Celsius(20.0))
+//!     }
+//! }
+//!
+//! let reading = Fixed.read().unwrap();
+//! println!("{}", Fahrenheit::from(reading.value));
+//! for value in reading.value.values() {
+//!     println!("{} = {} {}", value.name, value.value, value.unit);
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::time::SystemTime;
+
+/// Standard gravity, in m/s².
+pub const STANDARD_GRAVITY: f64 = 9.80665;
+
+/// The unit of a `Value`. Serializes as its symbol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Unit {
+    #[cfg_attr(feature = "serde", serde(rename = "g"))]
+    G,
+    #[cfg_attr(feature = "serde", serde(rename = "m/s²"))]
+    MetersPerSecondSquared,
+    #[cfg_attr(feature = "serde", serde(rename = "°C"))]
+    Celsius,
+    #[cfg_attr(feature = "serde", serde(rename = "°F"))]
+    Fahrenheit,
+    #[cfg_attr(feature = "serde", serde(rename = "%RH"))]
+    RelativeHumidity,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            Unit::G => "g",
+            Unit::MetersPerSecondSquared => "m/s²",
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::RelativeHumidity => "%RH",
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// One named number in a measurement, such as the x axis of an acceleration.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Value {
+    pub name: &'static str,
+    pub value: f64,
+    pub unit: Unit,
+}
+
+/// A measurement that can be broken into `Value`s.
+pub trait Measurement {
+    fn values(&self) -> Vec<Value>;
+}
+
+/// A measurement and when it was taken.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Reading<M> {
+    pub timestamp: SystemTime,
+    pub value: M,
+}
+
+/// A module driver that takes measurements.
+pub trait Sensor {
+    type Measurement: Measurement;
+
+    /// Takes a measurement.
+    fn measure(&mut self) -> io::Result<Self::Measurement>;
+
+    /// Takes a measurement and timestamps it.
+    fn read(&mut self) -> io::Result<Reading<Self::Measurement>> {
+        let value = try!(self.measure());
+        Ok(Reading {
+            timestamp: SystemTime::now(),
+            value: value,
+        })
+    }
+}
+
+macro_rules! unit {
+    ($(#[$attr:meta])* $name:ident, $unit:ident, $value:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default)]
+        #[cfg_attr(feature = "serde", derive(Serialize))]
+        pub struct $name(pub f64);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{} {}", self.0, Unit::$unit)
+            }
+        }
+
+        impl Measurement for $name {
+            fn values(&self) -> Vec<Value> {
+                vec![Value { name: $value, value: self.0, unit: Unit::$unit }]
+            }
+        }
+    }
+}
+
+unit!(
+    /// Acceleration in multiples of standard gravity.
+    G, G, "acceleration");
+unit!(MetersPerSecondSquared, MetersPerSecondSquared, "acceleration");
+unit!(Celsius, Celsius, "temperature");
+unit!(Fahrenheit, Fahrenheit, "temperature");
+unit!(
+    /// Relative humidity, in percent.
+    RelativeHumidity, RelativeHumidity, "humidity");
+
+impl From<G> for MetersPerSecondSquared {
+    fn from(g: G) -> MetersPerSecondSquared {
+        MetersPerSecondSquared(g.0 * STANDARD_GRAVITY)
+    }
+}
+
+impl From<MetersPerSecondSquared> for G {
+    fn from(a: MetersPerSecondSquared) -> G {
+        G(a.0 / STANDARD_GRAVITY)
+    }
+}
+
+impl From<Celsius> for Fahrenheit {
+    fn from(t: Celsius) -> Fahrenheit {
+        Fahrenheit(t.0 * 9.0 / 5.0 + 32.0)
+    }
+}
+
+impl From<Fahrenheit> for Celsius {
+    fn from(t: Fahrenheit) -> Celsius {
+        Celsius((t.0 - 32.0) * 5.0 / 9.0)
+    }
+}
+
+/// Acceleration along three axes.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Acceleration {
+    pub x: G,
+    pub y: G,
+    pub z: G,
+}
+
+impl Measurement for Acceleration {
+    fn values(&self) -> Vec<Value> {
+        vec![
+            Value { name: "x", value: self.x.0, unit: Unit::G },
+            Value { name: "y", value: self.y.0, unit: Unit::G },
+            Value { name: "z", value: self.z.0, unit: Unit::G },
+        ]
+    }
+}
+
+/// Temperature and relative humidity measured together.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Climate {
+    pub temperature: Celsius,
+    pub humidity: RelativeHumidity,
+}
+
+impl Measurement for Climate {
+    fn values(&self) -> Vec<Value> {
+        vec![
+            Value { name: "temperature", value: self.temperature.0, unit: Unit::Celsius },
+            Value { name: "humidity", value: self.humidity.0, unit: Unit::RelativeHumidity },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_units() {
+        assert_eq!(Fahrenheit(212.0), Fahrenheit::from(Celsius(100.0)));
+        assert_eq!(Celsius(-40.0), Celsius::from(Fahrenheit(-40.0)));
+        assert_eq!(MetersPerSecondSquared(STANDARD_GRAVITY * 2.0), G(2.0).into());
+        assert_eq!("21.5 °C", Celsius(21.5).to_string());
+
+        let climate = Climate { temperature: Celsius(20.0), humidity: RelativeHumidity(45.0) };
+        let values = climate.values();
+        assert_eq!("humidity", values[1].name);
+        assert_eq!(Unit::RelativeHumidity, values[1].unit);
+        assert_eq!("%RH", values[1].unit.symbol());
+    }
+}
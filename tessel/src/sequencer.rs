@@ -0,0 +1,436 @@
+//! Plays LED patterns on a background thread.
+//!
+//! A `Sequencer` takes ownership of the board's LEDs and plays a `Pattern`
+//! on them until told otherwise. `push` pre-empts the current pattern with
+//! another one, for example to flash an error code, and the previous
+//! pattern starts over once the pushed one finishes or is popped. Patterns
+//! can also be named after application states and shown with `show`.
+//!
+//! LED errors are ignored by the sequencer, so a status indicator can never
+//! take the program down.
+//! # Example
+//! ```rust,no_run
+//! use std::mem;
+//! use tessel::Tessel;
+//! use tessel::sequencer::{Pattern, Sequencer};
+//!
+//! let mut tessel = Tessel::new();
+//! let mut status = Sequencer::new(mem::replace(&mut tessel.led, vec![]));
+//! status.define("connecting", Pattern::blink_code(1, 2));
+//! status.define("online", Pattern::heartbeat(2));
+//! status.define("idle", Pattern::breathe(2));
+//!
+//! status.show("connecting");
+//! // ...
+//! status.show("online");
+//! // Flash SOS on the red LED three times, then go back to "online".
+//! status.push(Pattern::sos(0).times(3));
+//! ```
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use {LED, Trigger};
+
+// Blink code timing, in milliseconds.
+const BLINK_MS: u64 = 200;
+const BLINK_PAUSE_MS: u64 = 1000;
+// Morse code unit, in milliseconds.
+const MORSE_UNIT_MS: u64 = 150;
+// Breathing blinks the LED too fast to see over this period, in
+// milliseconds, holding each duty cycle for a step.
+const BREATHE_PERIOD_MS: u64 = 10;
+const BREATHE_STEP_MS: u64 = 100;
+
+/// One step of a pattern. LEDs are identified by their index in the
+/// sequencer's LEDs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Turns an LED on or off.
+    Set(usize, bool),
+    /// Hands an LED to a kernel trigger.
+    Trigger(usize, Trigger),
+    /// Leaves the LEDs as they are for a while.
+    Wait(Duration),
+}
+
+/// A sequence of steps, played in a loop or a fixed number of times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    steps: Vec<Step>,
+    // How many times to play the steps, or `None` to loop until replaced.
+    times: Option<u32>,
+}
+
+impl Pattern {
+    /// Creates a pattern that loops until replaced. A pattern without any
+    /// `Wait` steps is applied once and then holds.
+    pub fn new(steps: Vec<Step>) -> Pattern {
+        Pattern {
+            steps: steps,
+            times: None,
+        }
+    }
+
+    /// Plays the pattern `times` times instead of looping.
+    pub fn times(mut self, times: u32) -> Pattern {
+        self.times = Some(times);
+        self
+    }
+
+    /// Leaves every LED off.
+    pub fn off() -> Pattern {
+        Pattern::new(vec![])
+    }
+
+    /// Keeps an LED lit.
+    pub fn solid(led: usize) -> Pattern {
+        Pattern::new(vec![Step::Set(led, true)])
+    }
+
+    /// Blinks an LED `count` times, then pauses.
+    pub fn blink_code(led: usize, count: u32) -> Pattern {
+        let mut steps = vec![];
+        for _ in 0..count {
+            steps.push(Step::Set(led, true));
+            steps.push(Step::Wait(Duration::from_millis(BLINK_MS)));
+            steps.push(Step::Set(led, false));
+            steps.push(Step::Wait(Duration::from_millis(BLINK_MS)));
+        }
+        steps.push(Step::Wait(Duration::from_millis(BLINK_PAUSE_MS)));
+        Pattern::new(steps)
+    }
+
+    /// Signals SOS in Morse code on an LED.
+    pub fn sos(led: usize) -> Pattern {
+        let mut steps = vec![];
+        for &(units, count) in &[(1, 3), (3, 3), (1, 3)] {
+            for _ in 0..count {
+                steps.push(Step::Set(led, true));
+                steps.push(Step::Wait(Duration::from_millis(units * MORSE_UNIT_MS)));
+                steps.push(Step::Set(led, false));
+                steps.push(Step::Wait(Duration::from_millis(MORSE_UNIT_MS)));
+            }
+            // Make up the three unit gap between letters.
+            steps.push(Step::Wait(Duration::from_millis(2 * MORSE_UNIT_MS)));
+        }
+        // Make up the seven unit gap between words.
+        steps.push(Step::Wait(Duration::from_millis(4 * MORSE_UNIT_MS)));
+        Pattern::new(steps)
+    }
+
+    /// Double-flashes an LED with the kernel's heartbeat trigger, which
+    /// keeps going without waking the program.
+    pub fn heartbeat(led: usize) -> Pattern {
+        Pattern::new(vec![Step::Trigger(led, Trigger::Heartbeat)])
+    }
+
+    /// Fades an LED in and out. The Tessel's LEDs are either on or off, so
+    /// this ramps the duty cycle of the kernel's timer trigger instead.
+    pub fn breathe(led: usize) -> Pattern {
+        let mut steps = vec![];
+        // A delay of zero would make the kernel fall back to its default
+        // blink rate, so the duty cycle stays strictly between 0 and 1.
+        let fade_in = 1..BREATHE_PERIOD_MS;
+        let fade_out = (2..BREATHE_PERIOD_MS - 1).rev();
+        for on in fade_in.chain(fade_out) {
+            steps.push(Step::Trigger(led, Trigger::Timer {
+                delay_on: Duration::from_millis(on),
+                delay_off: Duration::from_millis(BREATHE_PERIOD_MS - on),
+            }));
+            steps.push(Step::Wait(Duration::from_millis(BREATHE_STEP_MS)));
+        }
+        Pattern::new(steps)
+    }
+}
+
+enum Message {
+    Play(Pattern),
+    Push(Pattern),
+    Pop,
+    Stop,
+}
+
+// Waits for the next message, for at most the given time if any. Tests
+// replace it to step through patterns without sleeping.
+type Idle = Box<FnMut(&Receiver<Message>, Option<Duration>) -> Result<Message, RecvTimeoutError> + Send>;
+
+fn idle(receiver: &Receiver<Message>, timeout: Option<Duration>) -> Result<Message, RecvTimeoutError> {
+    match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout),
+        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    }
+}
+
+/// Plays patterns on a set of LEDs from a background thread.
+pub struct Sequencer {
+    sender: Sender<Message>,
+    thread: Option<JoinHandle<Vec<LED>>>,
+    statuses: HashMap<String, Pattern>,
+}
+
+impl Sequencer {
+    /// Takes over `leds`, turning them all off.
+    pub fn new(leds: Vec<LED>) -> Sequencer {
+        Sequencer::with_idle(leds, Box::new(idle))
+    }
+
+    fn with_idle(leds: Vec<LED>, idle: Idle) -> Sequencer {
+        let (sender, receiver) = mpsc::channel();
+        Sequencer {
+            sender: sender,
+            thread: Some(thread::spawn(move || run(leds, receiver, idle))),
+            statuses: HashMap::new(),
+        }
+    }
+
+    /// Replaces every playing and pre-empted pattern with `pattern`.
+    pub fn play(&self, pattern: Pattern) {
+        let _ = self.sender.send(Message::Play(pattern));
+    }
+
+    /// Pre-empts the current pattern with `pattern`. The current pattern
+    /// starts over when `pattern` finishes or is popped.
+    pub fn push(&self, pattern: Pattern) {
+        let _ = self.sender.send(Message::Push(pattern));
+    }
+
+    /// Stops the most recently pushed pattern and restores the one before.
+    pub fn pop(&self) {
+        let _ = self.sender.send(Message::Pop);
+    }
+
+    /// Names a pattern after an application state.
+    pub fn define(&mut self, status: &str, pattern: Pattern) {
+        self.statuses.insert(status.to_string(), pattern);
+    }
+
+    /// Plays the pattern defined for `status`, as `play` would. Returns
+    /// false if no pattern has that name.
+    pub fn show(&self, status: &str) -> bool {
+        match self.statuses.get(status) {
+            Some(pattern) => {
+                self.play(pattern.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops the sequencer, turning the LEDs off and handing them back.
+    pub fn stop(mut self) -> Vec<LED> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Vec<LED> {
+        let _ = self.sender.send(Message::Stop);
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_default(),
+            None => vec![],
+        }
+    }
+}
+
+impl Drop for Sequencer {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+// Clears triggers and turns every LED off between patterns.
+fn reset(leds: &mut [LED]) {
+    for led in leds {
+        let _ = led.set_trigger(Trigger::None);
+        let _ = led.off();
+    }
+}
+
+fn apply(leds: &mut [LED], step: Step) {
+    match step {
+        Step::Set(index, on) => {
+            if let Some(led) = leds.get_mut(index) {
+                let _ = if on { led.on() } else { led.off() };
+            }
+        }
+        Step::Trigger(index, trigger) => {
+            if let Some(led) = leds.get_mut(index) {
+                let _ = led.set_trigger(trigger);
+            }
+        }
+        Step::Wait(_) => {}
+    }
+}
+
+// The sequencer thread. Returns the LEDs when stopped.
+fn run(mut leds: Vec<LED>, receiver: Receiver<Message>, mut idle: Idle) -> Vec<LED> {
+    // Pre-empted patterns, with the playing one last.
+    let mut stack: Vec<Pattern> = vec![];
+    let mut step = 0;
+    let mut played = 0;
+    reset(&mut leds);
+
+    loop {
+        let next = stack.last().map(|pattern| (pattern.steps.get(step).cloned(), pattern));
+        let message = match next {
+            // Nothing to play until told otherwise.
+            None => idle(&receiver, None).ok(),
+            Some((Some(Step::Wait(duration)), _)) => {
+                step += 1;
+                match idle(&receiver, Some(duration)) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => None,
+                }
+            }
+            Some((Some(other), _)) => {
+                apply(&mut leds, other);
+                step += 1;
+                continue;
+            }
+            // The end of the steps.
+            Some((None, pattern)) => {
+                played += 1;
+                let waits = pattern.steps.iter().any(|s| match *s {
+                    Step::Wait(_) => true,
+                    _ => false,
+                });
+                match pattern.times {
+                    Some(times) if played >= times => {
+                        stack.pop();
+                        reset(&mut leds);
+                        step = 0;
+                        played = 0;
+                        continue;
+                    }
+                    // Hold a pattern that would otherwise spin.
+                    None if !waits => idle(&receiver, None).ok(),
+                    _ => {
+                        step = 0;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        match message {
+            Some(Message::Play(pattern)) => {
+                stack.clear();
+                stack.push(pattern);
+            }
+            Some(Message::Push(pattern)) => stack.push(pattern),
+            Some(Message::Pop) => {
+                stack.pop();
+            }
+            Some(Message::Stop) | None => {
+                reset(&mut leds);
+                return leds;
+            }
+        }
+        reset(&mut leds);
+        step = 0;
+        played = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use {duration_ms, LED};
+    use self::tempfile::TempDir;
+
+    fn fake_led() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for &(attribute, value) in &[("brightness", "0"), ("max_brightness", "1"), ("trigger", "[none]")] {
+            File::create(dir.path().join(attribute)).unwrap().write_all(value.as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    fn is_on(dir: &Path) -> bool {
+        let mut value = String::new();
+        File::open(dir.join("brightness")).unwrap().read_to_string(&mut value).unwrap();
+        value == "1"
+    }
+
+    // Stands in for time passing: the sequencer reports each time it goes
+    // idle, then waits for the test to let time run out or send a message.
+    struct FakeClock {
+        idle: Receiver<Option<Duration>>,
+        elapse: Sender<bool>,
+    }
+
+    impl FakeClock {
+        fn new() -> (Idle, FakeClock) {
+            let (idle_tx, idle_rx) = mpsc::channel();
+            let (elapse_tx, elapse_rx) = mpsc::channel();
+            let idle = move |receiver: &Receiver<Message>, timeout| {
+                idle_tx.send(timeout).unwrap();
+                match elapse_rx.recv() {
+                    Ok(true) => Err(RecvTimeoutError::Timeout),
+                    Ok(false) => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    Err(_) => Err(RecvTimeoutError::Disconnected),
+                }
+            };
+            (Box::new(idle), FakeClock { idle: idle_rx, elapse: elapse_tx })
+        }
+
+        // Waits for the sequencer to settle, returning how long it would wait.
+        fn settled(&self) -> Option<Duration> {
+            self.idle.recv().unwrap()
+        }
+
+        fn elapse(&self) {
+            self.elapse.send(true).unwrap();
+        }
+
+        // Lets the sequencer take the next message.
+        fn deliver(&self) {
+            self.elapse.send(false).unwrap();
+        }
+    }
+
+    #[test]
+    fn pushed_pattern_restores_previous() {
+        let fakes = vec![fake_led(), fake_led()];
+        let dirs: Vec<&Path> = fakes.iter().map(|fake| fake.path()).collect();
+        let leds = dirs.iter().map(|dir| LED::with_dir(dir).unwrap()).collect();
+        let (idle, clock) = FakeClock::new();
+        let sequencer = Sequencer::with_idle(leds, idle);
+        assert_eq!(None, clock.settled());
+
+        sequencer.play(Pattern::solid(0));
+        clock.deliver();
+        assert_eq!(None, clock.settled());
+        assert!(is_on(dirs[0]) && !is_on(dirs[1]));
+
+        sequencer.push(Pattern::new(vec![Step::Set(1, true), Step::Wait(Duration::from_millis(200))]).times(1));
+        clock.deliver();
+        assert_eq!(Some(Duration::from_millis(200)), clock.settled());
+        assert!(!is_on(dirs[0]) && is_on(dirs[1]));
+
+        clock.elapse();
+        assert_eq!(None, clock.settled());
+        assert!(is_on(dirs[0]) && !is_on(dirs[1]));
+
+        clock.deliver();
+        sequencer.stop();
+        assert!(!is_on(dirs[0]) && !is_on(dirs[1]));
+    }
+
+    #[test]
+    fn breathe_ramps_timer_duty_cycle() {
+        let on_times: Vec<u64> = Pattern::breathe(0).steps.iter().filter_map(|step| match *step {
+            Step::Trigger(0, Trigger::Timer { delay_on, delay_off }) => {
+                assert_eq!(BREATHE_PERIOD_MS, duration_ms(delay_on) + duration_ms(delay_off));
+                Some(duration_ms(delay_on))
+            }
+            _ => None,
+        }).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 8, 7, 6, 5, 4, 3, 2], on_times);
+    }
+}
@@ -0,0 +1,147 @@
+//! Returns outputs to a safe state when a program stops.
+//!
+//! Pins and drivers register a `SafeState` describing how to leave their
+//! hardware, such as opening relays or disabling servo outputs. The state
+//! is applied when the `SafeState` is dropped, which includes unwinding
+//! from a panic, and by `apply_all`. Calling `handle_signals` also applies
+//! every registered state when the process receives SIGINT or SIGTERM,
+//! before exiting.
+//! # Example
+//! ```rust,no_run
+//! use tessel::{shutdown, Tessel};
+//!
+//! shutdown::handle_signals().unwrap();
+//!
+//! let (mut port_a, _) = Tessel::ports().unwrap();
+//! let (mut pump, _, _) = port_a.pins();
+//! // Switch the pump off however the program ends.
+//! pump.set_safe_state(false);
+//! pump.high().unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::process;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Exit status for a process stopped by a signal, as a shell would report
+// for SIGINT.
+const SIGNAL_EXIT_STATUS: i32 = 130;
+
+/// How long a safe state waits for hardware that another thread is using.
+pub const LOCK_TIMEOUT_MS: u64 = 500;
+
+type Action = Box<FnMut() + Send>;
+
+lazy_static! {
+    // Registered safe states that have not been applied yet.
+    static ref REGISTRY: Mutex<BTreeMap<usize, Action>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A registered safe state, applied once when dropped or by `apply_all`.
+pub struct SafeState {
+    id: usize,
+}
+
+impl SafeState {
+    /// Unregisters the safe state without applying it.
+    pub fn cancel(self) {
+        REGISTRY.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Drop for SafeState {
+    fn drop(&mut self) {
+        // Release the registry before running the action.
+        let action = REGISTRY.lock().unwrap().remove(&self.id);
+        if let Some(mut action) = action {
+            action();
+        }
+    }
+}
+
+/// Registers `action` to put some hardware into a safe state.
+pub fn register<F: FnMut() + Send + 'static>(action: F) -> SafeState {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    REGISTRY.lock().unwrap().insert(id, Box::new(action));
+    SafeState {
+        id: id,
+    }
+}
+
+/// Applies every registered safe state now, most recently registered first.
+pub fn apply_all() {
+    let actions = {
+        let mut registry = REGISTRY.lock().unwrap();
+        let ids: Vec<usize> = registry.keys().cloned().collect();
+        ids.into_iter().rev().filter_map(|id| registry.remove(&id)).collect::<Vec<_>>()
+    };
+    debug!(count = actions.len(), "applying safe states");
+    for mut action in actions {
+        action();
+    }
+}
+
+/// Locks `mutex` for a safe state action, giving up after `timeout` rather
+/// than blocking the signal handler behind a thread that may never let go.
+/// A lock poisoned by a panic is still taken.
+pub fn try_lock_for<T>(mutex: &Mutex<T>, timeout: Duration) -> Option<MutexGuard<T>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => return Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                if Instant::now() >= deadline {
+                    warn!(timeout = ?timeout, "gave up waiting for a lock to apply a safe state");
+                    return None;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+/// Applies every registered safe state and exits when the process receives
+/// SIGINT or SIGTERM.
+pub fn handle_signals() -> io::Result<()> {
+    ctrlc::set_handler(|| {
+        apply_all();
+        process::exit(SIGNAL_EXIT_STATUS);
+    }).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn safe_states_apply_once() {
+        let applied = Arc::new(Mutex::new(vec![]));
+
+        let log = applied.clone();
+        let first = register(move || log.lock().unwrap().push(1));
+        let log = applied.clone();
+        let second = register(move || log.lock().unwrap().push(2));
+        let log = applied.clone();
+        let cancelled = register(move || log.lock().unwrap().push(3));
+
+        cancelled.cancel();
+        drop(second);
+        drop(first);
+        assert_eq!(vec![2, 1], *applied.lock().unwrap());
+    }
+
+    #[test]
+    fn try_lock_for_gives_up() {
+        let mutex = Mutex::new(0);
+        let _held = mutex.lock().unwrap();
+        assert!(try_lock_for(&mutex, Duration::from_millis(5)).is_none());
+    }
+}
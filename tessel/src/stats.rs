@@ -0,0 +1,111 @@
+//! Counters describing a port's traffic, for charting bus health.
+//! # Example
+//! ```rust,no_run
+//! use tessel::Tessel;
+//!
+//! let (port_a, _) = Tessel::ports().unwrap();
+//! let stats = port_a.stats();
+//! println!("{} timeouts, {} NACKs, mean latency {:?}",
+//!          stats.timeouts, stats.nacks, stats.latency.mean());
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in microseconds. A final
+/// bucket counts everything slower.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// A snapshot of a port's counters since it was created.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Stats {
+    /// Commands sent, by command name such as "EnableI2c".
+    pub commands: BTreeMap<&'static str, u64>,
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    /// I2C operations the addressed device did not acknowledge.
+    pub nacks: u64,
+    /// Replies that did not arrive within the port's timeout.
+    pub timeouts: u64,
+    pub reconnections: u64,
+    /// Time from sending a command that expects a reply to receiving it.
+    pub latency: Histogram,
+}
+
+/// A histogram of durations over `LATENCY_BUCKETS_US`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            counts: vec![0; LATENCY_BUCKETS_US.len() + 1],
+            total: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let us = duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64;
+        let bucket = LATENCY_BUCKETS_US.iter().position(|&bound| us <= bound).unwrap_or(LATENCY_BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    /// The number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.total / count as u32),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Each bucket's upper bound, `None` for the last one, and count.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = LATENCY_BUCKETS_US.iter().map(|&us| Some(Duration::from_micros(us))).chain(Some(None));
+        bounds.zip(self.counts.iter().cloned()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(None, histogram.mean());
+        histogram.record(Duration::from_micros(80));
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(2));
+
+        let buckets = histogram.buckets();
+        assert_eq!((Some(Duration::from_micros(100)), 2), buckets[0]);
+        assert_eq!((Some(Duration::from_millis(5)), 1), buckets[5]);
+        assert_eq!((None, 1), buckets[12]);
+        assert_eq!(4, histogram.count());
+        assert_eq!(Duration::from_secs(2), histogram.max());
+        assert_eq!(Some(Duration::from_micros(500_795)), histogram.mean());
+    }
+}
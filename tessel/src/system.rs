@@ -0,0 +1,378 @@
+//! Information about the Tessel's operating system.
+//!
+//! Everything is read from `/proc`, `/sys` and `/etc`, relative to a root
+//! directory that defaults to `/` and can point at a fixture tree instead.
+//! With the `serde` feature enabled, `SystemInfo` and its parts implement
+//! `Serialize` so they can be returned from status endpoints.
+//! # Example
+//! ```rust,no_run
+//! use tessel::system;
+//!
+//! let info = system::info().unwrap();
+//! for interface in info.interfaces {
+//!     println!("{}: {:?}", interface.name, interface.addresses);
+//! }
+//! ```
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// IFF_UP in an interface's `flags`.
+const INTERFACE_UP: u32 = 0x1;
+
+/// A snapshot of everything `System` can read.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SystemInfo {
+    pub hostname: String,
+    /// The OpenWrt release, if the image has one.
+    pub release: Option<String>,
+    pub kernel: String,
+    pub uptime_secs: f64,
+    pub load: Load,
+    pub memory: Memory,
+    pub interfaces: Vec<Interface>,
+}
+
+/// Load averages over the last 1, 5 and 15 minutes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Load {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+/// Memory usage, in kilobytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Memory {
+    pub total_kb: u64,
+    pub free_kb: u64,
+    /// Memory available to new programs, including reclaimable caches.
+    pub available_kb: u64,
+}
+
+/// A network interface and its addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Interface {
+    pub name: String,
+    pub mac: Option<String>,
+    pub up: bool,
+    /// IPv4 and IPv6 addresses, IPv4 first.
+    pub addresses: Vec<String>,
+}
+
+/// Reads system information from a filesystem root.
+pub struct System {
+    root: PathBuf,
+}
+
+/// Reads all system information from `/`.
+pub fn info() -> io::Result<SystemInfo> {
+    System::new().info()
+}
+
+impl System {
+    pub fn new() -> System {
+        System::with_root("/")
+    }
+
+    /// Reads from `root` instead of `/`.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> System {
+        System {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Reads everything at once.
+    pub fn info(&self) -> io::Result<SystemInfo> {
+        Ok(SystemInfo {
+            hostname: try!(self.hostname()),
+            release: try!(self.release()),
+            kernel: try!(self.kernel()),
+            uptime_secs: try!(self.uptime_secs()),
+            load: try!(self.load()),
+            memory: try!(self.memory()),
+            interfaces: try!(self.interfaces()),
+        })
+    }
+
+    /// The device name, as set in the Tessel CLI.
+    pub fn hostname(&self) -> io::Result<String> {
+        Ok(try!(self.read("proc/sys/kernel/hostname")).trim().to_string())
+    }
+
+    /// The OpenWrt release description, or `None` if the image has none.
+    pub fn release(&self) -> io::Result<Option<String>> {
+        let release = match self.read("etc/openwrt_release") {
+            Ok(release) => release,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Shell assignments such as DISTRIB_DESCRIPTION='OpenWrt 15.05'.
+        Ok(release.lines()
+            .find(|line| line.starts_with("DISTRIB_DESCRIPTION="))
+            .map(|line| line["DISTRIB_DESCRIPTION=".len()..].trim().trim_matches(|c| c == '\'' || c == '"').to_string()))
+    }
+
+    /// The kernel release.
+    pub fn kernel(&self) -> io::Result<String> {
+        Ok(try!(self.read("proc/sys/kernel/osrelease")).trim().to_string())
+    }
+
+    /// Time since boot.
+    pub fn uptime(&self) -> io::Result<Duration> {
+        let secs = try!(self.uptime_secs());
+        Ok(Duration::new(secs as u64, (secs.fract() * 1e9) as u32))
+    }
+
+    fn uptime_secs(&self) -> io::Result<f64> {
+        parse_field(&try!(self.read("proc/uptime")), 0)
+    }
+
+    pub fn load(&self) -> io::Result<Load> {
+        let load = try!(self.read("proc/loadavg"));
+        Ok(Load {
+            one: try!(parse_field(&load, 0)),
+            five: try!(parse_field(&load, 1)),
+            fifteen: try!(parse_field(&load, 2)),
+        })
+    }
+
+    pub fn memory(&self) -> io::Result<Memory> {
+        let meminfo = try!(self.read("proc/meminfo"));
+        let field = |name: &str| -> io::Result<u64> {
+            match meminfo.lines().find(|line| line.starts_with(name) && line[name.len()..].starts_with(':')) {
+                Some(line) => parse_field(&line[name.len() + 1..], 0),
+                None => Err(invalid_data(name)),
+            }
+        };
+        let free = try!(field("MemFree"));
+        Ok(Memory {
+            total_kb: try!(field("MemTotal")),
+            free_kb: free,
+            // Kernels before 3.14 don't estimate available memory.
+            available_kb: field("MemAvailable").unwrap_or(free),
+        })
+    }
+
+    /// Lists network interfaces, sorted by name.
+    pub fn interfaces(&self) -> io::Result<Vec<Interface>> {
+        let mut interfaces = vec![];
+        for entry in try!(fs::read_dir(self.root.join("sys/class/net"))) {
+            let entry = try!(entry);
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let flags = try!(self.read(&format!("sys/class/net/{}/flags", name)));
+            let flags = try!(u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16)
+                .map_err(|_| invalid_data("flags")));
+            let mac = self.read(&format!("sys/class/net/{}/address", name)).ok()
+                .map(|mac| mac.trim().to_string())
+                .filter(|mac| !mac.is_empty());
+            interfaces.push(Interface {
+                name: name,
+                mac: mac,
+                up: flags & INTERFACE_UP != 0,
+                addresses: vec![],
+            });
+        }
+        interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for (name, address) in try!(self.ipv4_addresses()) {
+            if let Some(interface) = interfaces.iter_mut().find(|i| i.name == name) {
+                interface.addresses.push(address.to_string());
+            }
+        }
+        for (name, address) in try!(self.ipv6_addresses()) {
+            if let Some(interface) = interfaces.iter_mut().find(|i| i.name == name) {
+                interface.addresses.push(address.to_string());
+            }
+        }
+        Ok(interfaces)
+    }
+
+    // Local IPv4 addresses with their interfaces. /proc lists local
+    // addresses in the routing trie, without their interfaces, so each is
+    // matched to an interface through the most specific route covering it.
+    fn ipv4_addresses(&self) -> io::Result<Vec<(String, Ipv4Addr)>> {
+        let trie = try!(self.read("proc/net/fib_trie"));
+        let lines: Vec<&str> = trie.lines().collect();
+        let mut locals: Vec<Ipv4Addr> = vec![];
+        for pair in lines.windows(2) {
+            // A leaf such as "|-- 192.0.2.2" followed by "/32 host LOCAL".
+            if pair[1].trim() != "/32 host LOCAL" {
+                continue;
+            }
+            if let Ok(address) = pair[0].trim().trim_start_matches("|-- ").parse() {
+                if !locals.contains(&address) {
+                    locals.push(address);
+                }
+            }
+        }
+
+        // Routes as (interface, destination, mask), skipping the header.
+        let routes = try!(self.read("proc/net/route"));
+        let mut table = vec![];
+        for line in routes.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                continue;
+            }
+            let destination = try!(parse_route_address(fields[1]));
+            let mask = try!(parse_route_address(fields[7]));
+            table.push((fields[0], destination, mask));
+        }
+
+        Ok(locals.into_iter().filter_map(|address| {
+            let bits = u32::from(address);
+            let route = table.iter()
+                .filter(|&&(_, destination, mask)| mask != 0 && bits & mask == destination)
+                .max_by_key(|&&(_, _, mask)| mask.count_ones());
+            match route {
+                Some(&(name, _, _)) => Some((name.to_string(), address)),
+                // Loopback routes live in the local table, not /proc/net/route.
+                None if address.is_loopback() => Some(("lo".to_string(), address)),
+                None => None,
+            }
+        }).collect())
+    }
+
+    // IPv6 addresses with their interfaces.
+    fn ipv6_addresses(&self) -> io::Result<Vec<(String, Ipv6Addr)>> {
+        let inet6 = match self.read("proc/net/if_inet6") {
+            Ok(inet6) => inet6,
+            // IPv6 is disabled.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut addresses = vec![];
+        for line in inet6.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[0].len() != 32 {
+                return Err(invalid_data("if_inet6"));
+            }
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = try!(u16::from_str_radix(&fields[0][i * 4..i * 4 + 4], 16)
+                    .map_err(|_| invalid_data("if_inet6")));
+            }
+            addresses.push((fields[5].to_string(), Ipv6Addr::from(segments)));
+        }
+        Ok(addresses)
+    }
+
+    fn read(&self, path: &str) -> io::Result<String> {
+        let mut contents = String::new();
+        try!(try!(File::open(self.root.join(path))).read_to_string(&mut contents));
+        Ok(contents)
+    }
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse {}.", what))
+}
+
+// Parses the whitespace-separated field at `index`.
+fn parse_field<T: ::std::str::FromStr>(line: &str, index: usize) -> io::Result<T> {
+    line.split_whitespace().nth(index)
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| invalid_data(line.trim()))
+}
+
+// Route addresses are the raw network-order value printed as a native
+// integer.
+fn parse_route_address(hex: &str) -> io::Result<u32> {
+    u32::from_str_radix(hex, 16)
+        .map(u32::from_be)
+        .map_err(|_| invalid_data("route"))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    fn fixture(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn reads_fixture_tree() {
+        let fake = tempfile::tempdir().unwrap();
+        let root = fake.path();
+        fixture(&root, "proc/sys/kernel/hostname", "bulbasaur\n");
+        fixture(&root, "proc/sys/kernel/osrelease", "3.18.29\n");
+        fixture(&root, "etc/openwrt_release", "DISTRIB_ID='OpenWrt'\nDISTRIB_DESCRIPTION='OpenWrt Chaos Calmer 15.05.1'\n");
+        fixture(&root, "proc/uptime", "5025.52 9634.30\n");
+        fixture(&root, "proc/loadavg", "0.08 0.03 0.01 1/46 1234\n");
+        fixture(&root, "proc/meminfo", "MemTotal:          59976 kB\nMemFree:           19248 kB\nMemAvailable:      31196 kB\n");
+        fixture(&root, "proc/net/fib_trie", "\
+Main:
+  +-- 0.0.0.0/0 3 0 5
+     |-- 0.0.0.0
+        /0 universe UNICAST
+     +-- 127.0.0.0/8 2 0 2
+        +-- 127.0.0.0/31 1 0 0
+           |-- 127.0.0.0
+              /8 host LOCAL
+           |-- 127.0.0.1
+              /32 host LOCAL
+     +-- 192.168.1.0/24 2 0 2
+        +-- 192.168.1.0/28 2 0 2
+           |-- 192.168.1.0
+              /24 link UNICAST
+           |-- 192.168.1.5
+              /32 host LOCAL
+        |-- 192.168.1.255
+           /32 link BROADCAST
+Local:
+           |-- 192.168.1.5
+              /32 host LOCAL
+");
+        fixture(&root, "proc/net/route", "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+wlan0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+");
+        fixture(&root, "proc/net/if_inet6", "\
+00000000000000000000000000000001 01 80 10 80       lo
+fe8000000000000002a3b4fffec5d6e7 04 40 20 80    wlan0
+");
+        fixture(&root, "sys/class/net/lo/flags", "0x9\n");
+        fixture(&root, "sys/class/net/lo/address", "00:00:00:00:00:00\n");
+        fixture(&root, "sys/class/net/wlan0/flags", "0x1003\n");
+        fixture(&root, "sys/class/net/wlan0/address", "02:a3:b4:c5:d6:e7\n");
+
+        let info = System::with_root(&root).info().unwrap();
+
+        assert_eq!("bulbasaur", info.hostname);
+        assert_eq!(Some("OpenWrt Chaos Calmer 15.05.1".to_string()), info.release);
+        assert_eq!("3.18.29", info.kernel);
+        assert_eq!(5025.52, info.uptime_secs);
+        assert_eq!(Load { one: 0.08, five: 0.03, fifteen: 0.01 }, info.load);
+        assert_eq!(Memory { total_kb: 59976, free_kb: 19248, available_kb: 31196 }, info.memory);
+        assert_eq!(vec![
+            Interface {
+                name: "lo".to_string(),
+                mac: Some("00:00:00:00:00:00".to_string()),
+                up: true,
+                addresses: vec!["127.0.0.1".to_string(), "::1".to_string()],
+            },
+            Interface {
+                name: "wlan0".to_string(),
+                mac: Some("02:a3:b4:c5:d6:e7".to_string()),
+                up: true,
+                addresses: vec!["192.168.1.5".to_string(), "fe80::2a3:b4ff:fec5:d6e7".to_string()],
+            },
+        ], info.interfaces);
+    }
+}
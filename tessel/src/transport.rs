@@ -0,0 +1,218 @@
+//! Byte transports that can carry the port protocol.
+//!
+//! On a Tessel 2 the protocol runs over the Unix domain sockets exposed by
+//! spid, but anything that moves bytes in both directions will do. This lets
+//! a port be driven over TCP from another machine, or backed by an in-memory
+//! pipe so drivers can be tested without hardware.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use unix_socket::UnixStream;
+
+/// A bidirectional byte stream between a `PortSocket` and the coprocessor.
+pub trait Transport: Read + Write + Send {
+    /// Sets how long a read may block before failing with `WouldBlock` or
+    /// `TimedOut`. `None` blocks indefinitely. By default only `None` is
+    /// supported, so replies can't time out.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::Other, "Read timeouts are not supported.")),
+        }
+    }
+
+    /// Called before a sequence of commands and replies that must not be
+    /// interleaved with other users of the same port.
+    fn begin_transaction(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the sequence started by `begin_transaction` is complete.
+    fn end_transaction(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for Pipe {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+}
+
+/// Creates a connected pair of in-memory pipe ends.
+///
+/// Bytes written to one end can be read from the other. Reads block until
+/// data is available, and return end-of-file once the other end is dropped.
+/// # Example
+/// ```
+/// use std::io::prelude::*;
+/// use tessel::transport;
+///
+/// let (mut a, mut b) = transport::pipe();
+/// a.write_all(b"ping").unwrap();
+/// let mut buf = [0; 4];
+/// b.read_exact(&mut buf).unwrap();
+/// assert_eq!(b"ping", &buf);
+/// ```
+pub fn pipe() -> (Pipe, Pipe) {
+    let left = Arc::new(Channel::new());
+    let right = Arc::new(Channel::new());
+    (Pipe { rx: left.clone(), tx: right.clone(), read_timeout: None },
+     Pipe { rx: right, tx: left, read_timeout: None })
+}
+
+/// One end of an in-memory duplex pipe created with `pipe()`.
+pub struct Pipe {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+    read_timeout: Option<Duration>,
+}
+
+// A one-way byte queue shared between two pipe ends.
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+struct ChannelState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            state: Mutex::new(ChannelState {
+                buffer: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.rx.state.lock().unwrap();
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Pipe read timed out."));
+                    }
+                    self.rx.ready.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.rx.ready.wait(state).unwrap(),
+            };
+        }
+
+        let len = buf.len().min(state.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Pipe closed."));
+        }
+        state.buffer.extend(buf.iter().cloned());
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // Wake up the other end so it sees end-of-file or a broken pipe.
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pipe_is_duplex() {
+        let (mut a, mut b) = pipe();
+        a.write_all(&[1, 2, 3]).unwrap();
+        b.write_all(&[4]).unwrap();
+
+        let mut buf = [0; 3];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!([1, 2, 3], buf);
+        let mut buf = [0; 1];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!([4], buf);
+    }
+
+    #[test]
+    fn pipe_read_blocks_until_written() {
+        let (mut a, mut b) = pipe();
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 2];
+            b.read_exact(&mut buf).unwrap();
+            buf
+        });
+        a.write_all(&[0xAB, 0xCD]).unwrap();
+        assert_eq!([0xAB, 0xCD], reader.join().unwrap());
+    }
+
+    #[test]
+    fn pipe_reports_closed_peer() {
+        let (mut a, b) = pipe();
+        drop(b);
+
+        let mut buf = [0; 1];
+        assert_eq!(0, a.read(&mut buf).unwrap());
+        assert_eq!(io::ErrorKind::BrokenPipe, a.write(&[0]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn pipe_read_times_out() {
+        let (mut a, _b) = pipe();
+        a.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        let mut buf = [0; 1];
+        assert_eq!(io::ErrorKind::TimedOut, a.read(&mut buf).unwrap_err().kind());
+    }
+}
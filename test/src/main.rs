@@ -1,6 +1,5 @@
 #[macro_use] extern crate nickel;
 extern crate accel_mma84;
-extern crate local_ip;
 extern crate rustc_serialize;
 extern crate tessel;
 
@@ -8,7 +7,7 @@ use accel_mma84::Accelerometer;
 use nickel::{Nickel, HttpRouter, MediaType};
 use rustc_serialize::json;
 use std::sync::Mutex;
-use tessel::Tessel;
+use tessel::{system, Tessel};
 
 #[derive(RustcDecodable, RustcEncodable)]
 struct Measurement {
@@ -49,8 +48,14 @@ fn main() {
         json::encode(&reading).unwrap()
     });
 
-    // Print local IP
-    println!("LAN: http://{}/", local_ip::get().unwrap());
+    // Print the LAN addresses.
+    for interface in system::info().unwrap().interfaces {
+        for address in interface.addresses.iter().filter(|a| !a.contains(':')) {
+            if interface.name != "lo" {
+                println!("{}: http://{}/", interface.name, address);
+            }
+        }
+    }
 
     server.listen("0.0.0.0:80");
 }